@@ -36,6 +36,8 @@
                 fileset = lib.fileset.unions [
                   (config.rust-project.crane-lib.fileset.commonCargoSources unfilteredRoot)
                   (lib.fileset.maybeMissing ./templates)
+                  (lib.fileset.maybeMissing ./data)
+                  (lib.fileset.maybeMissing ./tests/fixtures)
                 ];
               };
 
@@ -1,19 +1,47 @@
+use crate::closure;
+use crate::config::NixOptions;
 use crate::detect::Driver;
-use crate::hash_store::HashStore;
-use anyhow::{bail, Context, Result};
+use crate::error::Error;
+use crate::hardware::Hardware;
+use crate::hash_store::{Entry, HashStore, Source};
+use crate::nix_hash::{sha256_sri, to_sri, HashMismatch};
+use crate::nix_log::{Display, Event, NixLog, Progress};
+use crate::nix_probe::{Frontend, NixInstall};
+use crate::plan::{Plan, PlannedHash};
+use crate::process;
+use crate::state::{Fingerprint, Provenance};
+use anyhow::{anyhow, bail, Context, Result};
 use handlebars::Handlebars;
-use regex::Regex;
+use log::info;
 use serde::Serialize;
 use std::{
     fs,
-    io::{BufRead, BufReader},
+    io::{self, Read, Seek, SeekFrom},
+    os::fd::AsFd,
     path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
+    time::Duration,
 };
 use tempfile::TempDir;
 
+/// Knobs shared by every Nix invocation.
+#[derive(Clone, Default)]
+pub struct BuildOptions {
+    pub progress: Progress,
+    /// Give up on a single Nix invocation after this long.
+    pub timeout: Option<Duration>,
+    /// Builders, store and other settings passed through to Nix.
+    pub nix: NixOptions,
+}
+
 /// Render the Nix expression from our Handlebars templates.
 pub fn render_nix_expr(driver: &Driver, hash: Option<&str>) -> Result<String> {
+    render_for(driver, hash, std::env::consts::ARCH)
+}
+
+/// The expression for a machine with CPU `arch`. The NVIDIA hash only goes
+/// into that architecture's attribute; the other one stays empty.
+fn render_for(driver: &Driver, hash: Option<&str>, arch: &str) -> Result<String> {
     let mesa_tpl = include_str!("../templates/nix-opengl-driver.mesa.nix.in");
     let nvidia_tpl = include_str!("../templates/nix-opengl-driver.nvidia.nix.in");
 
@@ -34,9 +62,14 @@ pub fn render_nix_expr(driver: &Driver, hash: Option<&str>) -> Result<String> {
             struct Substitutions<'a> {
                 version: &'a str,
                 sha256: &'a str,
+                aarch64: bool,
             }
 
-            let subs = Substitutions { version, sha256 };
+            let subs = Substitutions {
+                version,
+                sha256,
+                aarch64: arch == "aarch64",
+            };
             hb.render("nvidia", &subs)?
         }
         Driver::Mesa => hb.render("mesa", &())?,
@@ -50,90 +83,408 @@ fn write_nix_expr(dir: &Path, driver: &Driver, hash: Option<&str>) -> Result<()>
     Ok(())
 }
 
-fn run_nix(dir: &Path, quiet: bool) -> Result<(ExitStatus, String)> {
-    let mut child = Command::new("nix")
-        .args(["build", "-f", dir.to_str().unwrap(), "-o", "result"])
-        .current_dir(dir)
-        .stdout(if !quiet {
-            Stdio::inherit()
-        } else {
-            Stdio::null()
-        })
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("spawning `nix build`")?;
-
-    let mut stderr_buf = String::new();
-    if let Some(stderr) = child.stderr.take() {
-        let mut reader = BufReader::new(stderr);
-        let mut line = String::new();
-        while reader.read_line(&mut line)? > 0 {
-            eprint!("{line}");
-            stderr_buf.push_str(&line);
-            line.clear();
+/// `nix build -f <dir>` or, without a usable `nix` command, `nix-build <dir>`.
+/// A dry run realises nothing and logs plain text for [`Plan::parse_dry_run`];
+/// `nix build` additionally prints the output paths as JSON on stdout.
+fn build_command(dir: &Path, dry_run: bool, nix: &NixOptions) -> Result<Command> {
+    let install = NixInstall::get();
+    let (mut cmd, modern) = match (install.frontend(), install.nix_command()) {
+        (None, _) => return Err(Error::NixMissing(install.describe()).into()),
+        (Some(Frontend::Legacy), _) | (_, None) => {
+            let mut cmd = Command::new(install.nix_build.as_ref().unwrap());
+            cmd.arg(dir);
+            (cmd, false)
+        }
+        (Some(_), Some(mut cmd)) => {
+            cmd.args(["build", "-f"]).arg(dir);
+            (cmd, true)
+        }
+    };
+    if dry_run {
+        cmd.arg("--dry-run");
+        if modern {
+            cmd.arg("--json");
+        }
+    } else {
+        cmd.args(["-o", "result"]);
+        if install.supports_internal_json() {
+            cmd.args(["--log-format", "internal-json"]);
         }
     }
+    nix.apply(&mut cmd);
+    Ok(cmd)
+}
 
-    let status = child.wait().context("waiting on nix build")?;
-    Ok((status, stderr_buf))
+/// Where Nix's stdout goes: our stderr, never our stdout. `nix-build` prints
+/// the output path there, which would corrupt `--output json`; the path is
+/// read from the `result` link instead.
+fn nix_stdout(progress: Progress) -> Result<Stdio> {
+    if progress == Progress::None {
+        return Ok(Stdio::null());
+    }
+    let stderr = io::stderr()
+        .as_fd()
+        .try_clone_to_owned()
+        .context("duplicating stderr")?;
+    Ok(Stdio::from(stderr))
 }
 
-fn extract_hash(s: &str) -> Option<String> {
-    let re = Regex::new(r"got:\s*(sha256-[A-Za-z0-9+/=]+)").unwrap();
-    let cap = re.captures(s)?;
-    Some(cap[1].to_string())
+fn run_nix(dir: &Path, opts: &BuildOptions) -> Result<(ExitStatus, NixLog)> {
+    let mut cmd = build_command(dir, false, &opts.nix)?;
+    cmd.current_dir(dir).stdout(nix_stdout(opts.progress)?);
+
+    let mut log = NixLog::default();
+    let mut display = Display::new(opts.progress);
+    let status = process::run_streaming(&mut cmd, opts.timeout, |line| {
+        if let Some(ev) = Event::parse(line) {
+            log.apply(&ev);
+            display.show(&ev, &log);
+        }
+    });
+    display.finish();
+
+    Ok((status?, log))
+}
+
+/// `nix --version` output, e.g. `nix (Nix) 2.24.9`.
+fn nix_version() -> Option<String> {
+    NixInstall::get().version_line.clone()
+}
+
+/// The NVIDIA `.run` mismatch in Nix's output. Other fixed-output
+/// derivations (e.g. intel-ocl's zip) can mismatch too; never take theirs.
+fn nvidia_mismatch(log: &NixLog) -> Option<HashMismatch> {
+    log.mismatches()
+        .into_iter()
+        .find(|m| m.name.starts_with("NVIDIA-Linux-"))
+}
+
+fn extract_hash(log: &NixLog) -> Option<String> {
+    nvidia_mismatch(log).map(|m| m.got)
 }
 
-pub fn resolve_hash(driver: &Driver, quiet: bool) -> Result<String> {
+pub fn resolve_hash(driver: &Driver, opts: &BuildOptions) -> Result<String> {
     if let Driver::Nvidia(ver) = driver {
         let mut store = HashStore::load()?;
         // 1) If we already know this version → return it
-        if let Some(old) = store.get(ver) {
-            return Ok(old.clone());
+        if let Some((layer, old)) = store.lookup(ver) {
+            info!(
+                "using {} hash for NVIDIA {} from the {} store",
+                old.source, ver, layer
+            );
+            return Ok(old.hash.clone());
+        }
+        if opts.nix.is_offline() {
+            return Err(anyhow!(
+                "it isn't cached and can't be resolved offline; \
+                 sync once with network or add it with `hash-store set`"
+            )
+            .context(Error::HashUnresolved(ver.clone())));
         }
         // 2) Else do the two-phase Nix run as before…
         let tmp = TempDir::new().context("creating tempdir")?;
         let dir = tmp.path();
         write_nix_expr(dir, driver, None)?;
-        let (status, stderr) = run_nix(dir, quiet)?;
+        let (status, log) = run_nix(dir, opts)?;
         if status.success() {
             return Ok(String::new());
         }
-        let hash = extract_hash(&stderr).context("could not find sha256 in Nix output")?;
+        let hash = extract_hash(&log)
+            .context("could not find sha256 in Nix output")
+            .context(Error::HashUnresolved(ver.clone()))?;
 
         // 3) Persist it before returning
-        store.insert(ver.clone(), hash.clone())?;
+        store.insert(
+            ver.clone(),
+            Entry::new(hash.clone(), Source::Resolved, nix_version()),
+        )?;
         return Ok(hash);
     }
     // Not NVIDIA → no hash
     Ok(String::new())
 }
 
-pub fn build_farm(driver: &Driver, quiet: bool) -> Result<PathBuf> {
+pub fn build_farm(driver: &Driver, opts: &BuildOptions) -> Result<PathBuf> {
     let tmp = TempDir::new().context("creating tempdir")?;
     let dir = tmp.path();
 
     // 1) figure out the real hash (or "")
-    let sha = resolve_hash(driver, quiet).context("resolving hash before building")?;
+    let sha = resolve_hash(driver, opts).context("resolving hash before building")?;
 
     // 2) write expression with real hash
     write_nix_expr(dir, driver, Some(&sha))?;
+    if opts.nix.is_offline() {
+        ensure_local(dir, opts)?;
+    }
 
     // 3) build with live progress
-    let (status, stderr) = run_nix(dir, quiet)?;
+    let (status, log) = run_nix(dir, opts)?;
     if !status.success() {
-        bail!("`nix build` failed:\n{stderr}");
+        // A cached hash can go stale (bad manual edit, re-uploaded tarball).
+        // If Nix tells us the real one, evict the entry, store it and retry once.
+        let mut store = HashStore::load()?;
+        let Some(fresh) = heal_stale_hash(&mut store, driver, &sha, &log)? else {
+            return Err(
+                anyhow!("`nix build` failed{}", failure_summary(&log)).context(Error::BuildFailed)
+            );
+        };
+        write_nix_expr(dir, driver, Some(&fresh))?;
+        let (status, log) = run_nix(dir, opts)?;
+        if !status.success() {
+            return Err(anyhow!(
+                "`nix build` failed after re-resolving the NVIDIA hash{}",
+                failure_summary(&log)
+            )
+            .context(Error::BuildFailed));
+        }
     }
 
     // 4) return the canonicalized result link
     fs::canonicalize(dir.join("result")).context("resolving result")
 }
 
+/// Failed derivations and Nix's error messages, for error reports.
+fn failure_summary(log: &NixLog) -> String {
+    let mut out = String::new();
+    if !log.failed.is_empty() {
+        out.push_str(" (failed: ");
+        out.push_str(&log.failed.join(", "));
+        out.push(')');
+    }
+    out.push_str(":\n");
+    out.push_str(&log.text());
+    out
+}
+
+/// If Nix reported a hash mismatch for the NVIDIA driver and exactly the
+/// hash we used, replace the stored entry and return the corrected hash.
+fn heal_stale_hash(
+    store: &mut HashStore,
+    driver: &Driver,
+    used: &str,
+    log: &NixLog,
+) -> Result<Option<String>> {
+    let Driver::Nvidia(ver) = driver else {
+        return Ok(None);
+    };
+    let Some(m) = nvidia_mismatch(log) else {
+        return Ok(None);
+    };
+    // stored hashes may be hex or base32; Nix always reports SRI
+    let used_sri = to_sri(used);
+    if used_sri.is_none() || m.specified != used_sri || Some(&m.got) == used_sri.as_ref() {
+        return Ok(None);
+    }
+    let fresh = m.got;
+
+    let layer = store.lookup(ver).map(|(l, _)| l);
+    // Entries from read-only layers can't be evicted, only shadowed.
+    store.remove(ver)?;
+    eprintln!(
+        "⚠️  Warning: stale hash for NVIDIA {}: {} store had {}, Nix reported {}; updating hash store",
+        ver,
+        layer.map_or("no".to_string(), |l| l.to_string()),
+        used,
+        fresh
+    );
+    store.insert(
+        ver.clone(),
+        Entry::new(fresh.clone(), Source::Resolved, nix_version()),
+    )?;
+    Ok(Some(fresh))
+}
+
+/// Fingerprint the inputs of the farm for `driver`, without building.
+/// `None` if the NVIDIA hash isn't known yet, so the expression isn't either.
+pub fn fingerprint(driver: &Driver, opts: &BuildOptions) -> Result<Option<Fingerprint>> {
+    let hash = match driver {
+        Driver::Nvidia(ver) => match HashStore::load()?.get(ver) {
+            Some(h) => Some(h.to_string()),
+            None => return Ok(None),
+        },
+        Driver::Mesa => None,
+    };
+    let expr = render_nix_expr(driver, hash.as_deref())?;
+    Ok(Some(Fingerprint {
+        expr: sha256_sri(expr.as_bytes()),
+        driver: driver.to_string(),
+        nixpkgs: NixInstall::get().nixpkgs_revision(&opts.nix),
+        hardware: Hardware::probe(),
+    }))
+}
+
+/// Describe `farm`, built for `driver` in `took`.
+pub fn provenance(
+    driver: &Driver,
+    opts: &BuildOptions,
+    farm: &Path,
+    fingerprint: Option<&Fingerprint>,
+    took: Duration,
+) -> Result<Provenance> {
+    let nvidia_hash = match driver {
+        Driver::Nvidia(ver) => HashStore::load()?.get(ver).map(str::to_string),
+        Driver::Mesa => None,
+    };
+    let expr = render_nix_expr(driver, nvidia_hash.as_deref())?;
+    let mut packages: Vec<_> = closure::references(&farm.to_string_lossy(), &opts.nix)?
+        .iter()
+        .map(|p| closure::split_name(p))
+        .collect();
+    packages.sort();
+    Ok(Provenance {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        expr: sha256_sri(expr.as_bytes()),
+        nixpkgs: fingerprint.and_then(|f| f.nixpkgs.clone()),
+        packages,
+        nvidia_hash,
+        build_seconds: took.as_secs_f64(),
+    })
+}
+
+/// Work out what `build_farm` would do without building, fetching or
+/// resolving anything.
+pub fn dry_run(driver: &Driver, opts: &BuildOptions) -> Result<Plan> {
+    let mut plan = Plan {
+        driver: driver.to_string(),
+        ..Plan::default()
+    };
+    let hash = match driver {
+        Driver::Nvidia(ver) => match HashStore::load()?.lookup(ver) {
+            Some((layer, e)) => {
+                plan.nvidia_hash = Some(PlannedHash::Known {
+                    hash: e.hash.clone(),
+                    layer,
+                });
+                Some(e.hash.clone())
+            }
+            None => {
+                plan.nvidia_hash = Some(PlannedHash::Unresolved);
+                None
+            }
+        },
+        Driver::Mesa => None,
+    };
+
+    let tmp = TempDir::new().context("creating tempdir")?;
+    let dir = tmp.path();
+    write_nix_expr(dir, driver, hash.as_deref())?;
+    plan_dir(dir, opts, &mut plan)?;
+    Ok(plan)
+}
+
+/// Dry-run the expression already written to `dir` into `plan`.
+fn plan_dir(dir: &Path, opts: &BuildOptions, plan: &mut Plan) -> Result<()> {
+    let mut stdout = tempfile::tempfile().context("creating tempfile")?;
+    let mut cmd = build_command(dir, true, &opts.nix)?;
+    cmd.current_dir(dir).stdout(stdout.try_clone()?);
+    let mut stderr = String::new();
+    let status = process::run_streaming(&mut cmd, opts.timeout, |line| {
+        stderr.push_str(line);
+        stderr.push('\n');
+    })?;
+    if !status.success() {
+        bail!("`nix build --dry-run` failed:\n{stderr}");
+    }
+    plan.parse_dry_run(&stderr);
+
+    let mut json = String::new();
+    stdout.seek(SeekFrom::Start(0))?;
+    stdout.read_to_string(&mut json)?;
+    plan.out_path = serde_json::from_str::<serde_json::Value>(&json)
+        .ok()
+        .and_then(|v| v[0]["outputs"]["out"].as_str().map(str::to_string));
+    Ok(())
+}
+
+/// Offline, fail before building if anything would have to be downloaded,
+/// instead of hanging on the network halfway through.
+fn ensure_local(dir: &Path, opts: &BuildOptions) -> Result<()> {
+    let mut plan = Plan::default();
+    plan_dir(dir, opts, &mut plan)?;
+    let missing = plan.downloads();
+    if !missing.is_empty() {
+        bail!(
+            "offline, but {} path(s) are not in the store and need a download:\n  {}",
+            missing.len(),
+            missing.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
+    /// Point `fd` at `file` until the returned guard is dropped.
+    struct Redirect(libc::c_int, libc::c_int);
+
+    impl Redirect {
+        fn to(fd: libc::c_int, file: &fs::File) -> Redirect {
+            use std::os::fd::AsRawFd;
+            let saved = unsafe { libc::dup(fd) };
+            assert!(saved >= 0);
+            assert!(unsafe { libc::dup2(file.as_raw_fd(), fd) } >= 0);
+            Redirect(fd, saved)
+        }
+    }
+
+    impl Drop for Redirect {
+        fn drop(&mut self) {
+            unsafe {
+                libc::dup2(self.1, self.0);
+                libc::close(self.1);
+            }
+        }
+    }
+
+    #[test]
+    fn nix_stdout_never_reaches_ours() {
+        let _serial = process::tests::SERIAL
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let tmp = tempfile::tempdir().unwrap();
+        let out = fs::File::create(tmp.path().join("stdout")).unwrap();
+        let err = fs::File::create(tmp.path().join("stderr")).unwrap();
+        {
+            let _out = Redirect::to(libc::STDOUT_FILENO, &out);
+            let _err = Redirect::to(libc::STDERR_FILENO, &err);
+            // what the legacy `nix-build` prints after building
+            for progress in [Progress::Plain, Progress::None] {
+                let mut cmd = Command::new("sh");
+                cmd.args([
+                    "-c",
+                    "echo /nix/store/00000000000000000000000000000000-farm",
+                ])
+                .stdout(nix_stdout(progress).unwrap());
+                assert!(process::run_streaming(&mut cmd, None, |_| {})
+                    .unwrap()
+                    .success());
+            }
+        }
+        let read = |name| fs::read_to_string(tmp.path().join(name)).unwrap();
+        assert_eq!(read("stdout"), "");
+        assert_eq!(
+            read("stderr"),
+            "/nix/store/00000000000000000000000000000000-farm\n"
+        );
+    }
+
+    #[test]
+    fn hash_goes_to_the_machines_architecture_only() {
+        const HASH: &str = "sha256-LUPmTFgb5e9VTemIixqpADfvbUX1QoTT2dztwI3E3CY=";
+        let driver = Driver::Nvidia("570.133.07".into());
+
+        let x86 = render_for(&driver, Some(HASH), "x86_64").unwrap();
+        assert!(x86.contains(&format!(r#"sha256_64bit = "{HASH}";"#)));
+        assert!(x86.contains(r#"sha256_aarch64 = "";"#));
+
+        let arm = render_for(&driver, Some(HASH), "aarch64").unwrap();
+        assert!(arm.contains(r#"sha256_64bit = "";"#));
+        assert!(arm.contains(&format!(r#"sha256_aarch64 = "{HASH}";"#)));
+    }
+
     #[test]
     fn hash_is_properly_extracted() {
         const OUTPUT: &str = r#"
@@ -144,9 +495,77 @@ error: hash mismatch in fixed-output derivation '/nix/store/0sv2pvsyilwvi488kkjw
 error: 1 dependencies of derivation '/nix/store/d15fr4ik98n677hri556aqwkds0cz6sb-nvidia-x11-570.133.07.drv' failed to build
         "#;
 
+        let mut log = NixLog::default();
+        for line in OUTPUT.lines() {
+            log.apply(&Event::parse(line).unwrap());
+        }
+
         assert_eq!(
-            extract_hash(OUTPUT).as_deref(),
+            extract_hash(&log).as_deref(),
             Some("sha256-LUPmTFgb5e9VTemIixqpADfvbUX1QoTT2dztwI3E3CY=")
         );
     }
+
+    #[test]
+    fn only_heals_from_the_nvidia_mismatch() {
+        const USED: &str = "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        const OUTPUT: &str = r#"
+error: hash mismatch in fixed-output derivation '/nix/store/4kzn1f1ldm0q8hqa4ahmdll2jq0i6yql-SRB5_1_linux64.zip.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU=
+        "#;
+
+        let mut log = NixLog::default();
+        for line in OUTPUT.lines() {
+            log.apply(&Event::parse(line).unwrap());
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let mut store = HashStore::at(tmp.path().join("hashmap.json"));
+        store
+            .insert(
+                "570.133.07".into(),
+                Entry::new(USED.into(), Source::Manual, None),
+            )
+            .unwrap();
+        let driver = Driver::Nvidia("570.133.07".into());
+
+        assert_eq!(extract_hash(&log), None);
+        assert_eq!(
+            heal_stale_hash(&mut store, &driver, USED, &log).unwrap(),
+            None
+        );
+        assert_eq!(store.get("570.133.07"), Some(USED));
+    }
+
+    #[test]
+    fn heals_a_stale_entry_stored_as_hex() {
+        const USED: &str =
+            "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+        const GOT: &str = "sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU=";
+        const OUTPUT: &str = r#"
+error: hash mismatch in fixed-output derivation '/nix/store/0sv2pvsyilwvi488kkjw6mbx6h8sv4yv-NVIDIA-Linux-x86_64-570.133.07.run.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU=
+        "#;
+
+        let mut log = NixLog::default();
+        for line in OUTPUT.lines() {
+            log.apply(&Event::parse(line).unwrap());
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let mut store = HashStore::at(tmp.path().join("hashmap.json"));
+        store
+            .insert(
+                "570.133.07".into(),
+                Entry::new(USED.into(), Source::Manual, None),
+            )
+            .unwrap();
+        let driver = Driver::Nvidia("570.133.07".into());
+
+        assert_eq!(
+            heal_stale_hash(&mut store, &driver, USED, &log).unwrap(),
+            Some(GOT.into())
+        );
+        assert_eq!(store.get("570.133.07"), Some(GOT));
+    }
 }
@@ -0,0 +1,238 @@
+//! `check`: is this machine's driver setup healthy? Exits like a Nagios
+//! plugin so monitoring agents can run it as is.
+
+use crate::detect::Driver;
+use crate::service::SERVICE_PATH;
+use crate::state::{self, State, GCROOT_SYMLINK};
+use crate::tmpfiles::{RUN_SYMLINK, TMPFILES_CONF};
+use serde::Serialize;
+use std::{fs, path::Path};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ok,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    /// 0, 1 or 2, as monitoring plugins exit.
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Ok => "OK",
+            Severity::Warning => "WARNING",
+            Severity::Critical => "CRITICAL",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    /// Which check failed, e.g. `run_symlink`.
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    /// The worst severity among the failures.
+    pub status: Severity,
+    pub failures: Vec<Failure>,
+}
+
+fn fail(check: &'static str, severity: Severity, message: String) -> Option<Failure> {
+    Some(Failure {
+        check,
+        severity,
+        message,
+    })
+}
+
+/// `run` must be a symlink to `gcroot`, as the tmpfiles rule creates it.
+fn run_symlink(run: &Path, gcroot: &Path) -> Option<Failure> {
+    match fs::read_link(run) {
+        Ok(target) if target == gcroot => None,
+        Ok(target) => fail(
+            "run_symlink",
+            Severity::Critical,
+            format!(
+                "{} points at {}, not {}",
+                run.display(),
+                target.display(),
+                gcroot.display()
+            ),
+        ),
+        Err(_) => fail(
+            "run_symlink",
+            Severity::Critical,
+            format!("{} is missing or not a symlink", run.display()),
+        ),
+    }
+}
+
+/// The GC root must point at a farm that is still in the store.
+fn gcroot(gcroot: &Path) -> Option<Failure> {
+    let target = match fs::read_link(gcroot) {
+        Ok(t) => t,
+        Err(_) => {
+            return fail(
+                "gcroot",
+                Severity::Critical,
+                format!(
+                    "no GC root at {}; run `nix-opengl-driver sync`",
+                    gcroot.display()
+                ),
+            )
+        }
+    };
+    if !target.starts_with("/nix/store") || !target.exists() {
+        return fail(
+            "gcroot",
+            Severity::Critical,
+            format!("GC root target {} is not in the store", target.display()),
+        );
+    }
+    None
+}
+
+/// The last sync and the active farm must be for the driver and module
+/// version loaded now.
+pub fn state_matches(
+    state: Option<&State>,
+    farm: Option<&str>,
+    detected: &Driver,
+) -> Option<Failure> {
+    let detected = detected.to_string();
+    let Some(s) = state else {
+        return fail(
+            "state",
+            Severity::Critical,
+            "no state file; run `nix-opengl-driver sync`".into(),
+        );
+    };
+    if s.detected != detected {
+        return fail(
+            "state",
+            Severity::Critical,
+            format!(
+                "last sync was for {}, but {} is loaded",
+                s.detected, detected
+            ),
+        );
+    }
+    match farm {
+        Some(farm) if farm != detected => fail(
+            "state",
+            Severity::Critical,
+            format!("the active farm is for {farm}, but {detected} is loaded"),
+        ),
+        _ => None,
+    }
+}
+
+fn installed(check: &'static str, path: &str, what: &str, install: &str) -> Option<Failure> {
+    if Path::new(path).exists() {
+        return None;
+    }
+    fail(
+        check,
+        Severity::Warning,
+        format!("{what} is not installed at {path}; run `nix-opengl-driver {install}`"),
+    )
+}
+
+/// Run every check against this machine; `detected` is the driver the
+/// kernel has loaded, or why that couldn't be told.
+pub fn run(detected: anyhow::Result<Driver>) -> Report {
+    let farm = fs::read_link(GCROOT_SYMLINK)
+        .ok()
+        .filter(|t| t.exists())
+        .map(|t| state::farm_driver(&t));
+    let state = State::load();
+
+    let failures: Vec<_> = [
+        run_symlink(Path::new(RUN_SYMLINK), Path::new(GCROOT_SYMLINK)),
+        gcroot(Path::new(GCROOT_SYMLINK)),
+        match &detected {
+            Ok(d) => state_matches(state.as_ref(), farm.as_deref(), d),
+            Err(e) => fail("state", Severity::Critical, format!("{e:#}")),
+        },
+        installed(
+            "tmpfiles",
+            TMPFILES_CONF,
+            "the tmpfiles rule",
+            "tmpfiles-install",
+        ),
+        installed(
+            "service",
+            SERVICE_PATH,
+            "the sync service",
+            "service-install",
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    Report {
+        status: failures
+            .iter()
+            .map(|f| f.severity)
+            .max()
+            .unwrap_or(Severity::Ok),
+        failures,
+    }
+}
+
+impl Report {
+    /// One line per failing check, or a single OK line.
+    pub fn print(&self) {
+        if self.failures.is_empty() {
+            println!("OK: driver farm is active and matches the loaded driver");
+        }
+        for f in &self.failures {
+            println!("{}: {}", f.severity.label(), f.message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn run_symlink_must_point_at_the_gcroot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let run = tmp.path().join("opengl-driver");
+        let root = tmp.path().join("current");
+        assert!(run_symlink(&run, &root).is_some());
+
+        symlink(tmp.path().join("elsewhere"), &run).unwrap();
+        let f = run_symlink(&run, &root).unwrap();
+        assert_eq!(f.severity, Severity::Critical);
+
+        fs::remove_file(&run).unwrap();
+        symlink(&root, &run).unwrap();
+        assert!(run_symlink(&run, &root).is_none());
+    }
+
+    #[test]
+    fn state_must_match_the_loaded_module() {
+        let nvidia = Driver::Nvidia("570.133.07".into());
+        let s = State {
+            detected: "nvidia 570.133.07".into(),
+            ..State::default()
+        };
+        assert!(state_matches(Some(&s), Some("nvidia 570.133.07"), &nvidia).is_none());
+        assert!(state_matches(Some(&s), Some("nvidia 550.54.14"), &nvidia).is_some());
+        assert!(state_matches(Some(&s), None, &Driver::Nvidia("575.51.02".into())).is_some());
+        assert!(state_matches(None, None, &nvidia).is_some());
+    }
+}
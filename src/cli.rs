@@ -1,4 +1,11 @@
-use clap::{ArgGroup, Parser, Subcommand};
+use crate::build::BuildOptions;
+use crate::config::{Config, NixOptions, Publish};
+use crate::nix_log::Progress;
+use crate::utils;
+use anyhow::Result;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use log::info;
+use std::{path::PathBuf, time::Duration};
 
 /// Manage the Nix-based OpenGL driver symlink farm
 #[derive(Parser)]
@@ -13,6 +20,22 @@ pub struct Cli {
     #[arg(long)]
     pub quiet: bool,
 
+    /// Print results as `text` or as one `json` document on stdout
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = Output::Text)]
+    pub output: Output,
+
+    /// How to show Nix's build progress on stderr
+    #[arg(long, value_enum, default_value_t = Progress::Auto)]
+    pub progress: Progress,
+
+    /// Abort a Nix build that runs longer than this (e.g. `90s`, `10m`, `1h`)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Fail instead of waiting when another sync holds the lock
+    #[arg(long)]
+    pub no_wait: bool,
+
     /// Force using the Mesa software stack
     #[arg(long, group = "force")]
     pub force_mesa: bool,
@@ -25,26 +48,209 @@ pub struct Cli {
     #[arg(long)]
     pub resolve_hashes: bool,
 
+    /// Pass `--builders` to Nix, e.g. to build on `ssh://buildbox`
+    #[arg(long, value_name = "SPEC", help_heading = "Nix options")]
+    pub builders: Option<String>,
+
+    /// Pass `--store` to Nix
+    #[arg(long, value_name = "URL", help_heading = "Nix options")]
+    pub store: Option<String>,
+
+    /// Pass `--option substituters` to Nix (space-separated URLs)
+    #[arg(long, value_name = "URLS", help_heading = "Nix options")]
+    pub substituters: Option<String>,
+
+    /// Pass `--max-jobs` to Nix
+    #[arg(long, value_name = "N", help_heading = "Nix options")]
+    pub max_jobs: Option<String>,
+
+    /// Extra nix.conf lines for Nix, appended to `NIX_CONFIG`
+    #[arg(long, value_name = "CONF", help_heading = "Nix options")]
+    pub nix_config: Option<String>,
+
+    /// Only use the local store: no substituters and no downloads; `auto`
+    /// goes offline when there is no default route, `never` overrides the
+    /// config file
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "always",
+        help_heading = "Nix options"
+    )]
+    pub offline: Option<Offline>,
+
+    /// After `build` or `sync`, copy the farm's closure to this binary cache
+    #[arg(long, value_name = "URL", help_heading = "Publishing")]
+    pub publish_to: Option<String>,
+
+    /// Sign the closure with this secret key file before publishing
+    #[arg(long, value_name = "FILE", help_heading = "Publishing")]
+    pub sign_key: Option<PathBuf>,
+
     #[command(subcommand)]
     pub cmd: Commands,
 }
 
+impl Cli {
+    /// `--quiet` silences progress unless events were explicitly requested.
+    pub fn progress(&self) -> Progress {
+        if self.quiet && self.progress != Progress::Json {
+            Progress::None
+        } else {
+            self.progress
+        }
+    }
+
+    /// Nix settings from the config file, overridden by the command line.
+    pub fn build_options(&self) -> Result<BuildOptions> {
+        let nix = Config::load()?.nix.merge(NixOptions {
+            builders: self.builders.clone(),
+            store: self.store.clone(),
+            substituters: self.substituters.clone(),
+            max_jobs: self.max_jobs.clone(),
+            nix_config: self.nix_config.clone(),
+            offline: self.offline.map(|when| match when {
+                Offline::Never => false,
+                Offline::Always => true,
+                Offline::Auto => {
+                    let offline = !utils::has_default_route();
+                    if offline {
+                        info!("no default route, building offline");
+                    }
+                    offline
+                }
+            }),
+        });
+        Ok(BuildOptions {
+            progress: self.progress(),
+            timeout: self.timeout,
+            nix,
+        })
+    }
+
+    /// The binary cache to publish to, if the command line or config names one.
+    pub fn publish(&self) -> Result<Option<Publish>> {
+        let config = Config::load()?.publish;
+        let Some(to) = self
+            .publish_to
+            .clone()
+            .or_else(|| config.as_ref().map(|p| p.to.clone()))
+        else {
+            return Ok(None);
+        };
+        let secret_key = self
+            .sign_key
+            .clone()
+            .or_else(|| config.and_then(|p| p.secret_key));
+        Ok(Some(Publish { to, secret_key }))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Offline {
+    Never,
+    Auto,
+    Always,
+}
+
+/// `30`, `30s`, `10m`, `1h` or `7d`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = s
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((s, ""), |i| s.split_at(i));
+    let n: u64 = num
+        .parse()
+        .map_err(|_| format!("`{s}` is not a duration"))?;
+    let per = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("unknown unit `{unit}` in `{s}` (use s, m, h or d)")),
+    };
+    let secs = n
+        .checked_mul(per)
+        .ok_or_else(|| format!("`{s}` is too long"))?;
+    Ok(Duration::from_secs(secs))
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Show detected vs active driver and last sync info
-    Status,
+    Status {
+        /// Also show how the active farm was built and what it contains
+        #[arg(long, short)]
+        verbose: bool,
+    },
 
     /// Show only the detected (auto- or forced) driver
-    Driver,
+    Driver {
+        /// Write this machine's hardware profile as JSON (`-` for stdout)
+        #[arg(long, value_name = "FILE")]
+        export_profile: Option<PathBuf>,
+    },
 
     /// Print the Nix expression for the symlink farm
     Code,
 
     /// Build the symlink farm (prints store path; does not switch)
-    Build,
+    Build {
+        /// Only show what would be built and fetched
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Build for the machine described by this profile (see
+        /// `driver --export-profile`) instead of this one
+        #[arg(long, value_name = "FILE")]
+        profile: Option<PathBuf>,
+    },
 
     /// Build and switch the active symlink to the newly built farm
-    Sync,
+    Sync {
+        /// Only show what would be built, fetched and switched
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Print the package changes against the active farm before switching
+        #[arg(long, conflicts_with = "dry_run")]
+        diff: bool,
+
+        /// Do nothing if driver, expression, nixpkgs and hardware are unchanged
+        /// since the last sync and its farm is still in the store
+        #[arg(long)]
+        if_changed: bool,
+
+        /// If the NVIDIA farm fails to build and the active farm is for another
+        /// driver, switch to a Mesa farm instead
+        #[arg(long)]
+        fallback_mesa: bool,
+
+        /// Afterwards, write `metrics` to this file, whether the sync
+        /// succeeded or not
+        #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+        metrics_file: Option<PathBuf>,
+    },
+
+    /// Build the farm for the detected driver and compare it with the active one
+    Diff,
+
+    /// Check the installation's health; exits 0, 1 (warning) or 2 (critical)
+    Check,
+
+    /// Print Prometheus metrics for node_exporter's textfile collector
+    Metrics {
+        /// Write them atomically to this file instead of stdout
+        file: Option<PathBuf>,
+    },
 
     /// Print the tmpfiles.d rule for `/run/opengl-driver`
     Tmpfiles,
@@ -70,9 +276,98 @@ pub enum Commands {
     /// Uninstall all state, GC-root, tmpfiles rule, and service
     Uninstall,
 
+    /// List or delete earlier farms kept for rollback
+    Generations {
+        #[command(subcommand)]
+        cmd: Option<GenerationsCommand>,
+    },
+
+    /// Switch back to the previous generation, or to generation N
+    Rollback { generation: Option<u32> },
+
     /// Dump the raw JSON state file (or its backup)
-    State,
+    State {
+        #[command(subcommand)]
+        cmd: Option<StateCommand>,
+    },
+
+    /// Dump or edit the persisted NVIDIA version→hash map
+    HashStore {
+        #[command(subcommand)]
+        cmd: Option<HashStoreCommand>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HashStoreCommand {
+    /// List all entries, one `version hash layer source resolved-at nix-version` per line
+    ///
+    /// Layers are `user`, `global` and `embedded`, the seed table of this
+    /// architecture's releases built into the binary.
+    List,
+
+    /// Print the hash stored for a version
+    Get { version: String },
+
+    /// Store a hash for a version (any Nix hash encoding, stored as SRI)
+    Set { version: String, hash: String },
+
+    /// Remove the entry for a version from the writable store
+    Remove { version: String },
+
+    /// Remove entries with a malformed version or hash, and rewrite valid
+    /// non-SRI hashes as SRI
+    Prune,
+
+    /// Write every entry, from all layers, as JSON to a file (stdout if
+    /// omitted or `-`)
+    Export {
+        #[arg(value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+
+    /// Merge a JSON export from a file (or stdin with `-`), reporting conflicts
+    Import {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum GenerationsCommand {
+    /// List generations, marking the active one (the default)
+    List,
+    /// Drop the GC roots of old generations (never the active one)
+    #[command(group(ArgGroup::new("which").args(["older_than", "keep"]).required(true)))]
+    Delete {
+        /// Delete generations created longer ago than this (e.g. `30d`)
+        #[arg(long, value_name = "AGE", value_parser = parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Keep only the newest N generations
+        #[arg(long, value_name = "N")]
+        keep: Option<usize>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateCommand {
+    /// Dump the raw JSON state file, or its backup (the default)
+    Show,
+    /// Restore the state file from its backup, or rebuild it from the GC roots
+    Repair,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    /// Dump the persisted NVIDIA version→hash map
-    HashStore,
+    #[test]
+    fn durations_parse_without_overflowing() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172_800)));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("999999999999999d").is_err());
+    }
 }
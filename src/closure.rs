@@ -0,0 +1,257 @@
+use crate::config::NixOptions;
+use crate::nix_probe::NixInstall;
+use crate::output::say;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Changes smaller than this are noise (rebuilt paths with a different hash).
+const MIN_SIZE_DELTA: i64 = 8 * 1024;
+
+/// Every path in the closure of `path`, with its NAR size.
+pub fn closure(path: &str, nix: &NixOptions) -> Result<Vec<(String, u64)>> {
+    let paths = query(&["--query", "--requisites", path], nix)?;
+    let mut args = vec!["--query", "--size"];
+    args.extend(paths.iter().map(String::as_str));
+    let sizes = query(&args, nix)?;
+    if sizes.len() != paths.len() {
+        bail!(
+            "`nix-store --query --size` returned {} sizes for {} paths",
+            sizes.len(),
+            paths.len()
+        );
+    }
+    Ok(paths
+        .into_iter()
+        .zip(sizes.iter().map(|s| s.parse().unwrap_or(0)))
+        .collect())
+}
+
+/// Run `nix-store` with the configured store and settings, like every
+/// other Nix invocation.
+fn query(args: &[&str], nix: &NixOptions) -> Result<Vec<String>> {
+    let mut cmd = NixInstall::get().nix_store_command();
+    cmd.args(args);
+    nix.apply(&mut cmd);
+    let out = cmd.output().context("running nix-store")?;
+    if !out.status.success() {
+        bail!(
+            "`nix-store {}` failed:\n{}",
+            args[..2].join(" "),
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// The store paths `path` refers to directly; for a farm, its packages.
+pub fn references(path: &str, nix: &NixOptions) -> Result<Vec<String>> {
+    query(&["--query", "--references", path], nix)
+}
+
+/// `/nix/store/<hash>-mesa-25.0.5-drivers` → (`mesa`, `25.0.5`)
+///
+/// Like Nix's `DrvName`, the version starts at the first dash followed by
+/// something other than a letter.
+pub fn split_name(path: &str) -> (String, String) {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let name = base.split_once('-').map_or(base, |(_, n)| n);
+    let bytes = name.as_bytes();
+    for i in 0..bytes.len() {
+        if bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| !c.is_ascii_alphabetic()) {
+            let version = &name[i + 1..];
+            // drop multiple-output suffixes such as `-dev` or `-drivers`
+            let version = version
+                .split_once('-')
+                .filter(|(_, out)| out.chars().all(|c| c.is_ascii_alphabetic()))
+                .map_or(version, |(v, _)| v);
+            return (name[..i].to_string(), version.to_string());
+        }
+    }
+    (name.to_string(), String::new())
+}
+
+/// How one package differs between two closures.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub name: String,
+    pub old: BTreeSet<String>,
+    pub new: BTreeSet<String>,
+    pub size_delta: i64,
+}
+
+#[derive(Default)]
+struct Package {
+    versions: BTreeSet<String>,
+    size: u64,
+}
+
+fn packages(closure: &[(String, u64)]) -> BTreeMap<String, Package> {
+    let mut pkgs = BTreeMap::<String, Package>::new();
+    for (path, size) in closure {
+        let (name, version) = split_name(path);
+        let p = pkgs.entry(name).or_default();
+        p.size += size;
+        if !version.is_empty() {
+            p.versions.insert(version);
+        }
+    }
+    pkgs
+}
+
+/// Compare two closures package by package, like `nix store diff-closures`.
+pub fn diff(old: &[(String, u64)], new: &[(String, u64)]) -> Vec<Change> {
+    let old = packages(old);
+    let new = packages(new);
+    let names: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+    let empty = Package::default();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (o, n) = (old.get(name), new.get(name));
+            let size_delta = n.unwrap_or(&empty).size as i64 - o.unwrap_or(&empty).size as i64;
+            let changed = o.is_none()
+                || n.is_none()
+                || o.unwrap().versions != n.unwrap().versions
+                || size_delta.abs() >= MIN_SIZE_DELTA;
+            changed.then(|| Change {
+                name: name.clone(),
+                old: o.map(|p| p.versions.clone()).unwrap_or_default(),
+                new: n.map(|p| p.versions.clone()).unwrap_or_default(),
+                size_delta,
+            })
+        })
+        .collect()
+}
+
+fn versions(v: &BTreeSet<String>, present: bool) -> String {
+    match (present, v.is_empty()) {
+        (false, _) => "∅".to_string(),
+        (true, true) => "ε".to_string(),
+        (true, false) => v.iter().cloned().collect::<Vec<_>>().join(", "),
+    }
+}
+
+fn size(delta: i64) -> String {
+    format!("{:+.1} MiB", delta as f64 / (1024.0 * 1024.0))
+}
+
+/// The package changes between the active farm and a candidate.
+#[derive(Serialize)]
+pub struct Diff {
+    /// The active farm, `null` before the first sync.
+    pub from: Option<String>,
+    pub to: String,
+    pub changes: Vec<Change>,
+    /// Change in total closure size, in bytes.
+    pub size_delta: i64,
+    #[serde(skip)]
+    old_names: BTreeSet<String>,
+    #[serde(skip)]
+    new_names: BTreeSet<String>,
+}
+
+impl Diff {
+    pub fn new(
+        from: Option<String>,
+        to: String,
+        old: &[(String, u64)],
+        new: &[(String, u64)],
+    ) -> Diff {
+        let total = |c: &[(String, u64)]| c.iter().map(|(_, s)| *s as i64).sum::<i64>();
+        Diff {
+            from,
+            to,
+            changes: diff(old, new),
+            size_delta: total(new) - total(old),
+            old_names: packages(old).into_keys().collect(),
+            new_names: packages(new).into_keys().collect(),
+        }
+    }
+
+    /// Print one line per changed package and the total size delta.
+    pub fn print(&self) {
+        if self.changes.is_empty() {
+            say!("No package changes");
+        }
+        for c in &self.changes {
+            let from = versions(&c.old, self.old_names.contains(&c.name));
+            let to = versions(&c.new, self.new_names.contains(&c.name));
+            if from == to {
+                say!("{}: {}", c.name, size(c.size_delta));
+            } else {
+                say!("{}: {} → {}, {}", c.name, from, to, size(c.size_delta));
+            }
+        }
+        say!("Closure size: {}", size(self.size_delta));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(paths: &[(&str, u64)]) -> Vec<(String, u64)> {
+        paths.iter().map(|(p, s)| (p.to_string(), *s)).collect()
+    }
+
+    #[test]
+    fn splits_names_and_versions() {
+        let s = |p: &str| split_name(p);
+        assert_eq!(
+            s("/nix/store/2rqsbm2x3ilmcjgxp8ayc1hhn3ypxbzi-mesa-25.0.5-drivers"),
+            ("mesa".into(), "25.0.5".into())
+        );
+        assert_eq!(
+            s("/nix/store/7m3k3bhqk7r6fm8kpn4s0ydk9nx0wlrb-nvidia-x11-570.133.07"),
+            ("nvidia-x11".into(), "570.133.07".into())
+        );
+        assert_eq!(
+            s("/nix/store/r0ahk6mdw9mr5gky8g4dn9z4v0f6n4sj-nix-opengl-driver"),
+            ("nix-opengl-driver".into(), String::new())
+        );
+    }
+
+    #[test]
+    fn reports_version_changes_additions_and_removals() {
+        let old = c(&[
+            (
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-mesa-24.3.4",
+                100 << 20,
+            ),
+            (
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nvidia-x11-550.54.14",
+                300 << 20,
+            ),
+            (
+                "/nix/store/cccccccccccccccccccccccccccccccc-libva-2.22.0",
+                1 << 20,
+            ),
+        ]);
+        let new = c(&[
+            (
+                "/nix/store/dddddddddddddddddddddddddddddddd-mesa-25.0.5",
+                110 << 20,
+            ),
+            (
+                "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-libva-2.22.0",
+                1 << 20,
+            ),
+            (
+                "/nix/store/ffffffffffffffffffffffffffffffff-intel-ocl-5.0-63503",
+                40 << 20,
+            ),
+        ]);
+
+        let changes = diff(&old, &new);
+        let names: Vec<_> = changes.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["intel-ocl", "mesa", "nvidia-x11"]);
+        assert!(changes[0].old.is_empty());
+        assert_eq!(changes[1].size_delta, 10 << 20);
+        assert!(changes[2].new.is_empty());
+    }
+}
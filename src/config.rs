@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub const CONFIG_FILE: &str = "/etc/nix-opengl-driver/config.json";
+
+/// `/etc/nix-opengl-driver/config.json`; every field is optional.
+///
+/// ```json
+/// { "nix": { "builders": "ssh://buildbox x86_64-linux", "max_jobs": "0" } }
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub nix: NixOptions,
+    /// Binary cache to copy built farms to.
+    pub publish: Option<Publish>,
+    /// Prometheus textfile to rewrite after every `sync`.
+    pub metrics_file: Option<PathBuf>,
+}
+
+/// Where `build` and `sync` push the farm's closure.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Publish {
+    /// Store URL for `nix copy --to`, e.g. `file:///srv/cache` or `s3://…`.
+    pub to: String,
+    /// Secret key file to sign the closure with first.
+    pub secret_key: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::read(Path::new(CONFIG_FILE))
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(txt) => {
+                serde_json::from_str(&txt).with_context(|| format!("parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+}
+
+/// Settings passed through to every Nix invocation, so a thin client can
+/// e.g. offload the build to a remote builder.
+#[derive(Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NixOptions {
+    /// `--builders`, e.g. `ssh://buildbox x86_64-linux - 8`.
+    pub builders: Option<String>,
+    /// `--store`, e.g. `daemon` or `ssh-ng://buildbox`.
+    pub store: Option<String>,
+    /// `--option substituters`, space-separated URLs.
+    pub substituters: Option<String>,
+    /// `--max-jobs`, a number or `auto`.
+    pub max_jobs: Option<String>,
+    /// Extra `nix.conf` lines, appended to `NIX_CONFIG`.
+    pub nix_config: Option<String>,
+    /// Only use what's already in the store: no substituters, no downloads.
+    pub offline: Option<bool>,
+}
+
+impl NixOptions {
+    /// Fields set in `over` win.
+    pub fn merge(self, over: NixOptions) -> NixOptions {
+        NixOptions {
+            builders: over.builders.or(self.builders),
+            store: over.store.or(self.store),
+            substituters: over.substituters.or(self.substituters),
+            max_jobs: over.max_jobs.or(self.max_jobs),
+            nix_config: over.nix_config.or(self.nix_config),
+            offline: over.offline.or(self.offline),
+        }
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline == Some(true)
+    }
+
+    /// Add the flags to a `nix`, `nix-build` or `nix-instantiate` command.
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(b) = &self.builders {
+            cmd.args(["--builders", b]);
+        }
+        if let Some(s) = &self.store {
+            cmd.args(["--store", s]);
+        }
+        if let Some(s) = &self.substituters {
+            cmd.args(["--option", "substituters", s]);
+        }
+        if self.is_offline() {
+            cmd.args(["--option", "substitute", "false"]);
+        }
+        if let Some(j) = &self.max_jobs {
+            cmd.args(["--max-jobs", j]);
+        }
+        if let Some(conf) = &self.nix_config {
+            let conf = match std::env::var("NIX_CONFIG") {
+                Ok(prev) if !prev.is_empty() => format!("{prev}\n{conf}"),
+                _ => conf.clone(),
+            };
+            cmd.env("NIX_CONFIG", conf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_overrides_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(
+            &path,
+            r#"{ "nix": { "builders": "ssh://buildbox", "max_jobs": "0" } }"#,
+        )
+        .unwrap();
+
+        let opts = Config::read(&path).unwrap().nix.merge(NixOptions {
+            max_jobs: Some("4".into()),
+            substituters: Some("https://cache.nixos.org".into()),
+            ..NixOptions::default()
+        });
+        let mut cmd = Command::new("nix-build");
+        opts.apply(&mut cmd);
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            [
+                "--builders",
+                "ssh://buildbox",
+                "--option",
+                "substituters",
+                "https://cache.nixos.org",
+                "--max-jobs",
+                "4"
+            ]
+        );
+
+        assert!(Config::read(&dir.path().join("missing.json")).is_ok());
+    }
+
+    #[test]
+    fn cli_offline_overrides_config_either_way() {
+        let config = |offline| NixOptions {
+            offline,
+            ..NixOptions::default()
+        };
+        let cli = config;
+        assert!(!config(Some(true)).merge(cli(Some(false))).is_offline());
+        assert!(config(Some(false)).merge(cli(Some(true))).is_offline());
+        assert!(config(Some(true)).merge(cli(None)).is_offline());
+        assert!(!config(None).merge(cli(None)).is_offline());
+    }
+}
@@ -1,14 +1,25 @@
 use anyhow::{anyhow, Context, Result};
 use regex::Regex;
-use std::{fs, path::Path};
+use serde::Serialize;
+use std::{fmt, fs, path::Path};
 
 /// Which driver stack is active
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "driver", content = "version", rename_all = "lowercase")]
 pub enum Driver {
     Nvidia(String),
     Mesa,
 }
 
+impl fmt::Display for Driver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Driver::Nvidia(v) => write!(f, "nvidia {}", v),
+            Driver::Mesa => f.write_str("mesa"),
+        }
+    }
+}
+
 /// Detect NVIDIA vs Mesa
 pub fn detect_driver() -> Result<Driver> {
     let npath = Path::new("/proc/driver/nvidia/version");
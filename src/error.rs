@@ -0,0 +1,120 @@
+//! Failures scripts can branch on. Each kind has its own exit status,
+//! documented in the README; anything else exits with 1.
+
+use crate::process::Aborted;
+use serde::Serialize;
+use std::io;
+
+/// Raised (usually as context) where a classified failure happens.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("could not detect the GPU driver")]
+    DetectionFailed,
+    #[error("no Nix installation found: {0}")]
+    NixMissing(String),
+    #[error("building the farm failed")]
+    BuildFailed,
+    #[error("could not resolve the hash for NVIDIA {0}")]
+    HashUnresolved(String),
+    #[error("permission denied {0}")]
+    PermissionDenied(String),
+    #[error("another sync holds {0}")]
+    LockHeld(String),
+}
+
+/// What went wrong, as reported in `--output json` and the exit status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Other,
+    DetectionFailed,
+    NixMissing,
+    BuildFailed,
+    HashUnresolved,
+    PermissionDenied,
+    LockHeld,
+    Timeout,
+    Interrupted,
+}
+
+impl Kind {
+    /// The most specific kind anywhere in `e`'s chain: an aborted Nix run
+    /// beats the build failure it caused.
+    pub fn of(e: &anyhow::Error) -> Kind {
+        if let Some(a) = e.downcast_ref::<Aborted>() {
+            return match a {
+                Aborted::Timeout(..) => Kind::Timeout,
+                Aborted::Interrupted(..) => Kind::Interrupted,
+            };
+        }
+        if let Some(e) = e.downcast_ref::<Error>() {
+            return match e {
+                Error::DetectionFailed => Kind::DetectionFailed,
+                Error::NixMissing(_) => Kind::NixMissing,
+                Error::BuildFailed => Kind::BuildFailed,
+                Error::HashUnresolved(_) => Kind::HashUnresolved,
+                Error::PermissionDenied(_) => Kind::PermissionDenied,
+                Error::LockHeld(_) => Kind::LockHeld,
+            };
+        }
+        let denied = e
+            .chain()
+            .filter_map(|c| c.downcast_ref::<io::Error>())
+            .any(|e| e.kind() == io::ErrorKind::PermissionDenied);
+        if denied {
+            Kind::PermissionDenied
+        } else {
+            Kind::Other
+        }
+    }
+}
+
+/// Exit status for a failed run. 2 is left to usage errors.
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    if let Some(a) = e.downcast_ref::<Aborted>() {
+        return a.exit_code();
+    }
+    match Kind::of(e) {
+        Kind::Other => 1,
+        Kind::DetectionFailed => 3,
+        Kind::NixMissing => 4,
+        Kind::BuildFailed => 5,
+        Kind::HashUnresolved => 6,
+        Kind::PermissionDenied => 7,
+        Kind::LockHeld => 8,
+        Kind::Timeout => 124,
+        Kind::Interrupted => 130,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+    use std::time::Duration;
+
+    #[test]
+    fn classifies_through_context() {
+        let build = Err::<(), _>(anyhow!("`nix build` failed"))
+            .context(Error::BuildFailed)
+            .context("syncing")
+            .unwrap_err();
+        assert_eq!(Kind::of(&build), Kind::BuildFailed);
+        assert_eq!(exit_code(&build), 5);
+
+        let timeout = Err::<(), _>(anyhow::Error::from(Aborted::Timeout(
+            "nix".into(),
+            Duration::from_secs(1),
+        )))
+        .context(Error::BuildFailed)
+        .unwrap_err();
+        assert_eq!(Kind::of(&timeout), Kind::Timeout);
+        assert_eq!(exit_code(&timeout), 124);
+
+        let denied = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("removing /etc/x")
+            .unwrap_err();
+        assert_eq!(exit_code(&denied), 7);
+        assert_eq!(exit_code(&anyhow!("something else")), 1);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::detect::Driver;
+use crate::output::report;
+use crate::state::{Fingerprint, Generation, Provenance, State, GCROOT_SYMLINK};
+use crate::utils::pin_store_path;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Holds `current` and one `generation-N` root per generation.
+pub const GCROOT_DIR: &str = "/nix/var/nix/gcroots/nix-opengl-driver";
+
+fn root(n: u32) -> PathBuf {
+    Path::new(GCROOT_DIR).join(format!("generation-{n}"))
+}
+
+/// The generation `current` points at, if it was recorded.
+fn current(s: &State) -> Option<u32> {
+    let active = fs::read_link(GCROOT_SYMLINK)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| s.active.clone());
+    s.generations
+        .iter()
+        .rev()
+        .find(|g| g.path == active)
+        .map(|g| g.number)
+}
+
+/// Pin `farm` as a new generation, or refresh the newest one if it is the
+/// same farm. Returns its number.
+pub fn add(
+    farm: &Path,
+    driver: &Driver,
+    fingerprint: Option<Fingerprint>,
+    provenance: Option<Provenance>,
+) -> Result<u32> {
+    let path = farm.display().to_string();
+    let number = State::update(|s| {
+        if let Some(last) = s.generations.last_mut().filter(|g| g.path == path) {
+            last.fingerprint = fingerprint;
+            last.provenance = provenance;
+            return last.number;
+        }
+        let number = s.generations.last().map_or(1, |g| g.number + 1);
+        s.generations.push(Generation {
+            number,
+            path: path.clone(),
+            driver: driver.to_string(),
+            created: Utc::now().to_rfc3339(),
+            nixpkgs: fingerprint.as_ref().and_then(|f| f.nixpkgs.clone()),
+            fingerprint,
+            provenance,
+        });
+        number
+    })
+    .context("recording generation")?;
+    pin_store_path(&path, &root(number).to_string_lossy())
+        .with_context(|| format!("pinning generation {number}"))?;
+    Ok(number)
+}
+
+/// `generations --output json`
+#[derive(Serialize)]
+struct Listed<'a> {
+    /// `null` when `current` points at no recorded generation.
+    current: Option<u32>,
+    generations: &'a [Generation],
+}
+
+pub fn list() -> Result<()> {
+    let s = State::load().unwrap_or_default();
+    let current = current(&s);
+    let listed = Listed {
+        current,
+        generations: &s.generations,
+    };
+    report(&listed, || {
+        if s.generations.is_empty() {
+            println!("No generations yet (run `nix-opengl-driver sync`)");
+        }
+        for g in &s.generations {
+            println!(
+                "{} {:>4}  {}  {:<18}  {}  {}",
+                if Some(g.number) == current { "*" } else { " " },
+                g.number,
+                g.created,
+                g.driver,
+                g.nixpkgs.as_deref().unwrap_or("-"),
+                g.path
+            );
+        }
+    });
+    Ok(())
+}
+
+/// The newest generation before `current` with a different farm.
+fn previous(gens: &[Generation], current: Option<u32>) -> Option<&Generation> {
+    let Some(current) = current else {
+        return gens.last();
+    };
+    let path = &gens.iter().find(|g| g.number == current)?.path;
+    gens.iter()
+        .rev()
+        .find(|g| g.number < current && &g.path != path)
+}
+
+/// Make `target` the active farm in `s`. It was last synced when its
+/// generation was recorded.
+fn restore(s: &mut State, target: &Generation) {
+    s.active = target.path.clone();
+    s.detected = target.driver.clone();
+    s.fingerprint = target.fingerprint.clone();
+    s.provenance = target.provenance.clone();
+    s.last_sync = target.created.clone();
+    s.held = true;
+}
+
+/// Switch back to generation `n`, or the one before the current.
+pub fn rollback(n: Option<u32>) -> Result<()> {
+    let s = State::load().context("no state file; nothing to roll back to")?;
+    let current = current(&s);
+    let target = match n {
+        Some(n) => s
+            .generations
+            .iter()
+            .find(|g| g.number == n)
+            .with_context(|| format!("no generation {n}"))?,
+        None => previous(&s.generations, current).context("no earlier generation")?,
+    };
+    if !Path::new(&target.path).exists() {
+        bail!(
+            "the farm of generation {} is gone from the store: {}",
+            target.number,
+            target.path
+        );
+    }
+
+    pin_store_path(&target.path, GCROOT_SYMLINK).context("switching GC root")?;
+    State::update(|s| restore(s, target))?;
+    let text = format!(
+        "Rolled back to generation {}: {}",
+        target.number, target.path
+    );
+    report(
+        &serde_json::json!({ "generation": target.number, "path": target.path }),
+        || println!("{text}"),
+    );
+    Ok(())
+}
+
+/// Which generations `delete` removes: never the current one.
+fn to_delete(
+    gens: &[Generation],
+    current: Option<u32>,
+    now: DateTime<Utc>,
+    older_than: Option<Duration>,
+    keep: Option<usize>,
+) -> Vec<u32> {
+    let keep_from = keep.map_or(0, |k| gens.len().saturating_sub(k));
+    gens.iter()
+        .enumerate()
+        .filter(|(_, g)| Some(g.number) != current)
+        .filter(|(i, g)| match (older_than, keep) {
+            (Some(age), _) => DateTime::parse_from_rfc3339(&g.created)
+                .is_ok_and(|t| now.signed_duration_since(t).to_std().is_ok_and(|d| d > age)),
+            (None, Some(_)) => *i < keep_from,
+            (None, None) => false,
+        })
+        .map(|(_, g)| g.number)
+        .collect()
+}
+
+pub fn delete(older_than: Option<Duration>, keep: Option<usize>) -> Result<()> {
+    let s = State::load().unwrap_or_default();
+    let doomed = to_delete(&s.generations, current(&s), Utc::now(), older_than, keep);
+    if doomed.is_empty() {
+        report(&serde_json::json!({ "deleted": doomed }), || {
+            println!("No generations to delete")
+        });
+        return Ok(());
+    }
+    for &n in &doomed {
+        match fs::remove_file(root(n)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("removing generation {n}")),
+        }
+    }
+    State::update(|s| s.generations.retain(|g| !doomed.contains(&g.number)))?;
+    report(&serde_json::json!({ "deleted": doomed }), || {
+        println!(
+            "Deleted generation(s) {}; run `nix-collect-garbage` to free their farms",
+            doomed
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    });
+    Ok(())
+}
+
+/// Remove every `generation-N` root, for `uninstall`.
+pub fn remove_all() -> Result<()> {
+    let entries = match fs::read_dir(GCROOT_DIR) {
+        Ok(e) => e,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("listing generations"),
+    };
+    for e in entries.flatten() {
+        if e.file_name().to_string_lossy().starts_with("generation-") {
+            fs::remove_file(e.path())
+                .with_context(|| format!("removing {}", e.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generation(number: u32, path: &str, created: &str) -> Generation {
+        Generation {
+            number,
+            path: path.into(),
+            driver: "mesa".into(),
+            created: created.into(),
+            nixpkgs: None,
+            fingerprint: None,
+            provenance: None,
+        }
+    }
+
+    fn gens() -> Vec<Generation> {
+        vec![
+            generation(1, "/nix/store/a", "2025-01-01T00:00:00+00:00"),
+            generation(2, "/nix/store/b", "2025-02-01T00:00:00+00:00"),
+            generation(3, "/nix/store/b", "2025-03-01T00:00:00+00:00"),
+            generation(4, "/nix/store/c", "2025-04-01T00:00:00+00:00"),
+        ]
+    }
+
+    #[test]
+    fn rollback_skips_generations_with_the_same_farm() {
+        let g = gens();
+        assert_eq!(previous(&g, Some(4)).map(|g| g.number), Some(3));
+        assert_eq!(previous(&g, Some(3)).map(|g| g.number), Some(1));
+        assert_eq!(previous(&g, Some(1)).map(|g| g.number), None);
+        assert_eq!(previous(&g, None).map(|g| g.number), Some(4));
+    }
+
+    #[test]
+    fn rollback_restores_the_targets_build_info() {
+        let provenance = |build_seconds| Provenance {
+            tool_version: "0.1.0".into(),
+            expr: "sha256-AAAA".into(),
+            nixpkgs: None,
+            packages: vec![],
+            nvidia_hash: None,
+            build_seconds,
+        };
+        let mut old = generation(1, "/nix/store/a", "2025-01-01T00:00:00+00:00");
+        old.provenance = Some(provenance(12.0));
+        let mut s = State {
+            active: "/nix/store/c".into(),
+            detected: "nvidia 575.51.02".into(),
+            last_sync: "2025-04-01T00:00:00+00:00".into(),
+            provenance: Some(provenance(300.0)),
+            ..State::default()
+        };
+
+        restore(&mut s, &old);
+        assert_eq!(s.active, "/nix/store/a");
+        assert_eq!(s.detected, "mesa");
+        assert_eq!(s.last_sync, "2025-01-01T00:00:00+00:00");
+        assert_eq!(s.provenance.as_ref().map(|p| p.build_seconds), Some(12.0));
+        assert!(s.held);
+
+        // recorded before generations kept their provenance
+        restore(
+            &mut s,
+            &generation(2, "/nix/store/b", "2025-02-01T00:00:00+00:00"),
+        );
+        assert!(s.provenance.is_none());
+    }
+
+    #[test]
+    fn delete_never_touches_the_current_generation() {
+        let g = gens();
+        let now = DateTime::parse_from_rfc3339("2025-04-15T00:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let days = |d: u64| Some(Duration::from_secs(d * 86400));
+
+        assert_eq!(to_delete(&g, Some(4), now, None, Some(2)), [1, 2]);
+        assert_eq!(to_delete(&g, Some(1), now, None, Some(2)), [2]);
+        assert_eq!(to_delete(&g, Some(4), now, days(60), None), [1, 2]);
+        assert_eq!(to_delete(&g, Some(1), now, days(60), None), [2]);
+        assert!(to_delete(&g, Some(4), now, days(365), None).is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+/// PCI class prefix of display controllers (VGA, 3D, other).
+const DISPLAY_CLASS: &str = "0x03";
+
+/// A display controller on the PCI bus.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Gpu {
+    pub slot: String,
+    /// `vendor:device`, e.g. `10de:2684`.
+    pub id: String,
+    /// Kernel module bound to it, if any.
+    pub module: Option<String>,
+}
+
+impl fmt::Display for Gpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.slot, self.id)?;
+        if let Some(m) = &self.module {
+            write!(f, " ({m})")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the farm was built for, beyond the driver version.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hardware {
+    pub gpus: Vec<Gpu>,
+    /// Version (or srcversion for in-tree modules) of each GPU module.
+    pub modules: BTreeMap<String, String>,
+    pub kernel: String,
+}
+
+impl Hardware {
+    pub fn probe() -> Self {
+        Self::probe_at(Path::new("/sys"), Path::new("/proc"))
+    }
+
+    fn probe_at(sys: &Path, proc: &Path) -> Self {
+        let read = |p: &Path| fs::read_to_string(p).ok().map(|s| s.trim().to_string());
+        let hex = |p: &Path| read(p).map(|s| s.trim_start_matches("0x").to_string());
+
+        let mut gpus = Vec::new();
+        let mut modules = BTreeMap::new();
+        if let Ok(entries) = fs::read_dir(sys.join("bus/pci/devices")) {
+            for e in entries.flatten() {
+                let dev = e.path();
+                if !read(&dev.join("class")).is_some_and(|c| c.starts_with(DISPLAY_CLASS)) {
+                    continue;
+                }
+                let (Some(vendor), Some(device)) =
+                    (hex(&dev.join("vendor")), hex(&dev.join("device")))
+                else {
+                    continue;
+                };
+                let module = fs::read_link(dev.join("driver/module"))
+                    .ok()
+                    .and_then(|m| Some(m.file_name()?.to_string_lossy().into_owned()));
+                if let Some(m) = &module {
+                    let dir = sys.join("module").join(m);
+                    if let Some(v) =
+                        read(&dir.join("version")).or_else(|| read(&dir.join("srcversion")))
+                    {
+                        modules.insert(m.clone(), v);
+                    }
+                }
+                gpus.push(Gpu {
+                    slot: e.file_name().to_string_lossy().into_owned(),
+                    id: format!("{vendor}:{device}"),
+                    module,
+                });
+            }
+        }
+        gpus.sort();
+
+        Hardware {
+            gpus,
+            modules,
+            kernel: read(&proc.join("sys/kernel/osrelease")).unwrap_or_default(),
+        }
+    }
+
+    /// What changed going from `self` to `new`, one line each.
+    pub fn changes(&self, new: &Hardware) -> Vec<String> {
+        let mut out = Vec::new();
+        for g in &self.gpus {
+            if !new.gpus.contains(g) {
+                out.push(format!("GPU removed: {g}"));
+            }
+        }
+        for g in &new.gpus {
+            if !self.gpus.contains(g) {
+                out.push(format!("GPU added: {g}"));
+            }
+        }
+        let names: std::collections::BTreeSet<_> =
+            self.modules.keys().chain(new.modules.keys()).collect();
+        for m in names {
+            match (self.modules.get(m), new.modules.get(m)) {
+                (Some(a), Some(b)) if a != b => out.push(format!("Module {m}: {a} → {b}")),
+                (Some(a), None) => out.push(format!("Module {m} {a} no longer loaded")),
+                (None, Some(b)) => out.push(format!("Module {m} {b} loaded")),
+                _ => {}
+            }
+        }
+        if self.kernel != new.kernel {
+            out.push(format!("Kernel: {} → {}", self.kernel, new.kernel));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn probes_display_controllers_from_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        let (sys, proc) = (root.path().join("sys"), root.path().join("proc"));
+        let w = |p: &Path, s: &str| {
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, s).unwrap();
+        };
+
+        let gpu = sys.join("bus/pci/devices/0000:01:00.0");
+        w(&gpu.join("class"), "0x030000\n");
+        w(&gpu.join("vendor"), "0x10de\n");
+        w(&gpu.join("device"), "0x2684\n");
+        w(&sys.join("module/nvidia/version"), "570.133.07\n");
+        fs::create_dir_all(gpu.join("driver")).unwrap();
+        symlink(sys.join("module/nvidia"), gpu.join("driver/module")).unwrap();
+
+        let nic = sys.join("bus/pci/devices/0000:02:00.0");
+        w(&nic.join("class"), "0x020000\n");
+        w(&nic.join("vendor"), "0x8086\n");
+        w(&nic.join("device"), "0x15f3\n");
+
+        w(&proc.join("sys/kernel/osrelease"), "6.12.4\n");
+
+        let hw = Hardware::probe_at(&sys, &proc);
+        assert_eq!(
+            hw.gpus,
+            [Gpu {
+                slot: "0000:01:00.0".into(),
+                id: "10de:2684".into(),
+                module: Some("nvidia".into()),
+            }]
+        );
+        assert_eq!(hw.modules["nvidia"], "570.133.07");
+        assert_eq!(hw.kernel, "6.12.4");
+    }
+
+    #[test]
+    fn explains_changes() {
+        let gpu = |id: &str, m: &str| Gpu {
+            slot: "0000:01:00.0".into(),
+            id: id.into(),
+            module: Some(m.into()),
+        };
+        let old = Hardware {
+            gpus: vec![gpu("10de:2684", "nvidia")],
+            modules: [("nvidia".into(), "565.77".into())].into(),
+            kernel: "6.12.4".into(),
+        };
+        let new = Hardware {
+            gpus: vec![gpu("10de:2684", "nvidia")],
+            modules: [("nvidia".into(), "570.133.07".into())].into(),
+            kernel: "6.12.4".into(),
+        };
+        assert_eq!(old.changes(&old), Vec::<String>::new());
+        assert_eq!(old.changes(&new), ["Module nvidia: 565.77 → 570.133.07"]);
+
+        let swapped = Hardware {
+            gpus: vec![gpu("1002:744c", "amdgpu")],
+            modules: BTreeMap::new(),
+            kernel: "6.13.1".into(),
+        };
+        assert_eq!(old.changes(&swapped).len(), 4);
+    }
+}
@@ -1,29 +1,215 @@
-use anyhow::{Context, Result};
+use crate::lock::{lock_path, write_atomic, FileLock};
+use crate::nix_hash::to_sri;
+use crate::output::{report, say};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
 use dirs::data_local_dir;
 use libc::geteuid;
+use log::warn;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
 
 /// Global path used by root‐run services
 const GLOBAL_STORE: &str = "/var/lib/nix-opengl-driver/hashmap.json";
 
+/// Known-good hashes for released NVIDIA drivers, compiled into the binary.
+/// Only a seed, keyed by CPU architecture since the `.run` files differ;
+/// versions missing from it are resolved on first use.
+const EMBEDDED_STORE: &str = include_str!("../data/nvidia-hashes.json");
+
+/// Where a lookup found its entry, in the order layers are consulted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    User,
+    Global,
+    Embedded,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layer::User => "user",
+            Layer::Global => "global",
+            Layer::Embedded => "embedded",
+        })
+    }
+}
+
+/// How an entry's hash was obtained.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Resolved by a Nix build on this machine.
+    Resolved,
+    /// Set by hand with `hash-store set`.
+    Manual,
+    /// Merged in with `hash-store import`.
+    Imported,
+    /// Shipped in the binary.
+    Embedded,
+    /// Written by an older version that only stored the hash.
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Resolved => "resolved",
+            Source::Manual => "manual",
+            Source::Imported => "imported",
+            Source::Embedded => "embedded",
+            Source::Unknown => "unknown",
+        })
+    }
+}
+
+/// A stored hash together with its provenance.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "EntryRepr")]
+pub struct Entry {
+    pub hash: String,
+    pub source: Source,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nix_version: Option<String>,
+}
+
+impl Entry {
+    pub fn new(hash: String, source: Source, nix_version: Option<String>) -> Self {
+        Entry {
+            hash,
+            source,
+            resolved_at: Some(Utc::now().to_rfc3339()),
+            nix_version,
+        }
+    }
+}
+
+/// On-disk form of an entry: older stores map versions straight to hashes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EntryRepr {
+    Hash(String),
+    Full {
+        hash: String,
+        #[serde(default)]
+        source: Source,
+        resolved_at: Option<String>,
+        nix_version: Option<String>,
+    },
+}
+
+impl From<EntryRepr> for Entry {
+    fn from(r: EntryRepr) -> Self {
+        match r {
+            EntryRepr::Hash(hash) => Entry {
+                hash,
+                source: Source::Unknown,
+                resolved_at: None,
+                nix_version: None,
+            },
+            EntryRepr::Full {
+                hash,
+                source,
+                resolved_at,
+                nix_version,
+            } => Entry {
+                hash,
+                source,
+                resolved_at,
+                nix_version,
+            },
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Default)]
 struct Mapping {
-    map: HashMap<String, String>,
+    map: BTreeMap<String, Entry>,
 }
 
+impl Mapping {
+    fn read(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Mapping::default());
+        }
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("reading hash store at {}", path.display()))?;
+        serde_json::from_str(&s).with_context(|| format!("parsing JSON in {}", path.display()))
+    }
+
+    fn embedded() -> Self {
+        Self::embedded_for(std::env::consts::ARCH)
+    }
+
+    /// The embedded entries for `arch`; none for an architecture it lacks.
+    fn embedded_for(arch: &str) -> Self {
+        let mut all: BTreeMap<String, Mapping> =
+            serde_json::from_str(EMBEDDED_STORE).expect("embedded hash table is valid JSON");
+        let mut m = all.remove(arch).unwrap_or_default();
+        for e in m.map.values_mut() {
+            e.source = Source::Embedded;
+        }
+        m
+    }
+}
+
+/// An imported entry whose hash disagrees with the one we already have.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub version: String,
+    /// The layer lookups currently take `ours` from.
+    pub layer: Layer,
+    pub ours: String,
+    pub theirs: String,
+}
+
+/// Outcome of merging another hash map into the store.
+#[derive(Debug, Default)]
+pub struct Merge {
+    /// New versions; only written if there are no conflicts.
+    pub added: Vec<String>,
+    pub unchanged: usize,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Outcome of pruning the store.
+#[derive(Debug, Default)]
+pub struct Pruned {
+    /// Entries with a malformed version or hash.
+    pub removed: Vec<(String, Entry)>,
+    /// Versions whose valid hex or base32 hash was rewritten as SRI.
+    pub normalized: Vec<String>,
+}
+
+/// The writable hash store, backed by read-only fallback layers.
+///
+/// Lookups consult the user store, then the global store, then the table
+/// embedded in the binary. Writes only ever touch the writable layer: the
+/// global store when running as root, otherwise the per-user one.
 pub struct HashStore {
     data: Mapping,
     path: PathBuf,
+    layer: Layer,
+    fallback: Vec<(Layer, Mapping)>,
 }
 
 impl HashStore {
-    /// Pick the correct path: global if root, else per-user.
-    fn store_path() -> PathBuf {
+    /// Pick the writable layer: global if root, else per-user.
+    fn store_path() -> (Layer, PathBuf) {
         // if running as root, use the global path
         if unsafe { geteuid() } == 0 {
-            PathBuf::from(GLOBAL_STORE)
+            (Layer::Global, PathBuf::from(GLOBAL_STORE))
         } else {
             // per-user cache under $XDG_STATE_HOME/nix-opengl-driver
             let mut p = data_local_dir()
@@ -36,33 +222,176 @@ impl HashStore {
                 })
                 .join("nix-opengl-driver");
             p.push("hashmap.json");
-            p
+            (Layer::User, p)
         }
     }
 
-    /// Load existing or start empty.
+    /// Load the writable layer (or start empty) plus its fallbacks.
     pub fn load() -> Result<Self> {
-        let path = Self::store_path();
-        let data = if path.exists() {
-            let s = fs::read_to_string(&path)
-                .with_context(|| format!("reading hash store at {}", path.display()))?;
-            serde_json::from_str(&s)
-                .with_context(|| format!("parsing JSON in {}", path.display()))?
-        } else {
-            Mapping::default()
-        };
-        Ok(HashStore { data, path })
+        let (layer, path) = Self::store_path();
+        let data = Mapping::read(&path)?;
+
+        let mut fallback = Vec::new();
+        if layer == Layer::User {
+            // the global store is root's; a broken or unreadable one shouldn't stop us
+            match Mapping::read(Path::new(GLOBAL_STORE)) {
+                Ok(m) => fallback.push((Layer::Global, m)),
+                Err(e) => warn!("ignoring global hash store: {e:#}"),
+            }
+        }
+        fallback.push((Layer::Embedded, Mapping::embedded()));
+
+        Ok(HashStore {
+            data,
+            path,
+            layer,
+            fallback,
+        })
+    }
+
+    /// A user-layer store at `path` without fallbacks.
+    #[cfg(test)]
+    pub fn at(path: PathBuf) -> Self {
+        HashStore {
+            data: Mapping::default(),
+            path,
+            layer: Layer::User,
+            fallback: vec![],
+        }
+    }
+
+    /// Find the first layer that knows this version.
+    pub fn lookup(&self, version: &str) -> Option<(Layer, &Entry)> {
+        std::iter::once((self.layer, &self.data))
+            .chain(self.fallback.iter().map(|(l, m)| (*l, m)))
+            .find_map(|(l, m)| m.map.get(version).map(|e| (l, e)))
     }
 
-    /// If we have a mapping for this version, return it.
-    pub fn get(&self, version: &str) -> Option<&String> {
-        self.data.map.get(version)
+    /// If any layer has a mapping for this version, return its hash.
+    pub fn get(&self, version: &str) -> Option<&str> {
+        self.lookup(version).map(|(_, e)| e.hash.as_str())
     }
 
-    /// Insert and immediately persist to disk.
+    /// Every known version with the entry that wins the lookup.
+    pub fn entries(&self) -> BTreeMap<&str, (Layer, &Entry)> {
+        let mut all = BTreeMap::new();
+        let layers = std::iter::once((self.layer, &self.data))
+            .chain(self.fallback.iter().map(|(l, m)| (*l, m)));
+        for (layer, m) in layers {
+            for (v, e) in &m.map {
+                all.entry(v.as_str()).or_insert((layer, e));
+            }
+        }
+        all
+    }
+
+    /// Insert into the writable layer and immediately persist to disk.
     /// On PermissionDenied, prints a warning and continues.
-    pub fn insert(&mut self, version: String, hash: String) -> Result<()> {
-        self.data.map.insert(version.clone(), hash.clone());
+    pub fn insert(&mut self, version: String, entry: Entry) -> Result<()> {
+        self.update(|map| {
+            map.insert(version, entry);
+        })
+    }
+
+    /// Drop the writable layer's mapping for this version (if any) and
+    /// persist to disk. Returns the evicted entry.
+    pub fn remove(&mut self, version: &str) -> Result<Option<Entry>> {
+        self.update(|map| map.remove(version))
+    }
+
+    /// Add every entry from `incoming` no layer knows yet and persist once.
+    /// Entries whose hash differs from the one lookups find, in any layer,
+    /// are reported, never shadowed; if there are any, nothing is added.
+    pub fn merge(&mut self, incoming: BTreeMap<String, Entry>) -> Result<Merge> {
+        let lower: BTreeMap<String, (Layer, String)> = incoming
+            .keys()
+            .filter_map(|v| {
+                self.fallback
+                    .iter()
+                    .find_map(|(l, m)| m.map.get(v).map(|e| (v.clone(), (*l, e.hash.clone()))))
+            })
+            .collect();
+        let layer = self.layer;
+        self.update(|map| {
+            let mut merge = Merge::default();
+            let mut new = Vec::new();
+            for (version, theirs) in incoming {
+                let ours = match map.get(&version) {
+                    Some(e) => Some((layer, e.hash.clone())),
+                    None => lower.get(&version).cloned(),
+                };
+                match ours {
+                    None => {
+                        merge.added.push(version.clone());
+                        new.push((version, theirs));
+                    }
+                    Some((_, ours)) if ours == theirs.hash => merge.unchanged += 1,
+                    Some((layer, ours)) => merge.conflicts.push(Conflict {
+                        version,
+                        layer,
+                        ours,
+                        theirs: theirs.hash,
+                    }),
+                }
+            }
+            // all or nothing, so a failed import leaves nothing half-done
+            if merge.conflicts.is_empty() {
+                map.extend(new);
+            }
+            merge
+        })
+    }
+
+    /// Drop entries with a malformed version or hash, persist, and return them.
+    pub fn prune(&mut self) -> Result<Pruned> {
+        self.update(|map| {
+            let mut pruned = Pruned::default();
+            for (version, mut e) in std::mem::take(map) {
+                match to_sri(&e.hash) {
+                    Some(sri) if is_valid_version(&version) => {
+                        if sri != e.hash {
+                            e.hash = sri;
+                            pruned.normalized.push(version.clone());
+                        }
+                        map.insert(version, e);
+                    }
+                    _ => pruned.removed.push((version, e)),
+                }
+            }
+            pruned
+        })
+    }
+
+    /// Apply `f` to the writable layer under an exclusive lock. The file is
+    /// re-read first so a concurrent writer's entries aren't clobbered.
+    /// On PermissionDenied, prints a warning and keeps the change in memory.
+    fn update<T>(&mut self, f: impl FnOnce(&mut BTreeMap<String, Entry>) -> T) -> Result<T> {
+        let lock = match FileLock::exclusive(&lock_path(&self.path)) {
+            Ok(lock) => Some(lock),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                eprintln!(
+                    "⚠️  Warning: cannot lock hash store `{}`: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+            Err(e) => return Err(e).context("locking hash store"),
+        };
+
+        if lock.is_some() {
+            self.data = Mapping::read(&self.path)?;
+        }
+        let out = f(&mut self.data.map);
+        if lock.is_some() {
+            self.persist()?;
+        }
+        Ok(out)
+    }
+
+    /// Atomically write the current mapping back to `self.path`.
+    /// On PermissionDenied, prints a warning and continues.
+    fn persist(&self) -> Result<()> {
         let json_txt =
             serde_json::to_string_pretty(&self.data).context("serializing hash store")?;
 
@@ -81,7 +410,7 @@ impl HashStore {
             }
         }
 
-        if let Err(e) = fs::write(path, json_txt) {
+        if let Err(e) = write_atomic(path, json_txt.as_bytes()) {
             if e.kind() == std::io::ErrorKind::PermissionDenied {
                 eprintln!(
                     "⚠️  Warning: cannot write hash store `{}`: {}",
@@ -97,10 +426,365 @@ impl HashStore {
     }
 }
 
-/// Pretty-print the loaded store to stdout.
+/// An entry as shown to users: with the layer it came from.
+#[derive(Serialize)]
+struct Shown<'a> {
+    layer: Layer,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+fn shown(hs: &HashStore) -> BTreeMap<&str, Shown<'_>> {
+    hs.entries()
+        .into_iter()
+        .map(|(v, (layer, entry))| (v, Shown { layer, entry }))
+        .collect()
+}
+
+/// Pretty-print every layer's winning entries, with provenance, to stdout.
 pub fn print_store() -> Result<()> {
     let hs = HashStore::load().context("loading hash store")?;
-    let out = json!({ "map": hs.data.map });
+    let out = json!({ "map": shown(&hs) });
     println!("{}", serde_json::to_string_pretty(&out)?);
     Ok(())
 }
+
+/// Print one `version hash layer source resolved-at nix-version` line per entry.
+pub fn list_entries() -> Result<()> {
+    let hs = HashStore::load().context("loading hash store")?;
+    report(&json!({ "map": shown(&hs) }), || {
+        for (version, (layer, e)) in hs.entries() {
+            println!(
+                "{version} {} {layer} {} {} {}",
+                e.hash,
+                e.source,
+                e.resolved_at.as_deref().unwrap_or("-"),
+                e.nix_version.as_deref().unwrap_or("-"),
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Print the hash stored for `version`.
+pub fn get_entry(version: &str) -> Result<()> {
+    let hs = HashStore::load().context("loading hash store")?;
+    match hs.get(version) {
+        Some(hash) => report(&json!({ "version": version, "hash": hash }), || {
+            println!("{hash}")
+        }),
+        None => bail!("no hash stored for NVIDIA {version}"),
+    }
+    Ok(())
+}
+
+/// Store `hash` (any Nix encoding, normalized to SRI) for `version`.
+pub fn set_entry(version: &str, hash: &str) -> Result<()> {
+    if !is_valid_version(version) {
+        bail!("`{version}` is not a valid NVIDIA driver version");
+    }
+    let sri = to_sri(hash).with_context(|| format!("`{hash}` is not a valid sha256 hash"))?;
+    let mut hs = HashStore::load().context("loading hash store")?;
+    hs.insert(
+        version.to_string(),
+        Entry::new(sri.clone(), Source::Manual, None),
+    )?;
+    report(&json!({ "version": version, "hash": sri }), || {
+        println!("{version} {sri}")
+    });
+    Ok(())
+}
+
+/// Remove the entry for `version` from the writable layer.
+pub fn remove_entry(version: &str) -> Result<()> {
+    let mut hs = HashStore::load().context("loading hash store")?;
+    match hs.remove(version)? {
+        Some(e) => report(&json!({ "removed": { version: e } }), || {
+            println!("Removed {version} {}", e.hash)
+        }),
+        None => match hs.lookup(version) {
+            Some((layer, _)) => bail!(
+                "NVIDIA {version} is only in the {layer} store, which can't be changed from \
+                 the {} store; `hash-store set` it to override it",
+                hs.layer
+            ),
+            None => bail!("no hash stored for NVIDIA {version}"),
+        },
+    }
+    Ok(())
+}
+
+/// Remove malformed entries.
+pub fn prune_entries() -> Result<()> {
+    let mut hs = HashStore::load().context("loading hash store")?;
+    let pruned = hs.prune()?;
+    let removed: BTreeMap<_, _> = pruned.removed.iter().map(|(v, e)| (v, e)).collect();
+    let out = json!({ "removed": removed, "normalized": pruned.normalized });
+    report(&out, || {
+        for (version, e) in &pruned.removed {
+            println!("Removed {version} {}", e.hash);
+        }
+        for version in &pruned.normalized {
+            println!("Normalized {version}");
+        }
+        println!(
+            "Pruned {} entries, normalized {}",
+            pruned.removed.len(),
+            pruned.normalized.len()
+        );
+    });
+    Ok(())
+}
+
+/// Write every entry lookups resolve, tagged with its layer, as JSON to
+/// `file`, or stdout for `None` / `-`. `import` ignores the tag.
+pub fn export(file: Option<&Path>) -> Result<()> {
+    let hs = HashStore::load().context("loading hash store")?;
+    let txt = serde_json::to_string_pretty(&json!({ "map": shown(&hs) }))?;
+    match file.filter(|f| *f != Path::new("-")) {
+        Some(f) => {
+            write_atomic(f, (txt + "\n").as_bytes())
+                .with_context(|| format!("writing {}", f.display()))?;
+            report(&json!({ "written": f }), || {});
+        }
+        None => println!("{txt}"),
+    }
+    Ok(())
+}
+
+/// Merge a JSON export from `file` (or stdin for `-`) into the store.
+pub fn import(file: &Path) -> Result<()> {
+    let txt = if file == Path::new("-") {
+        let mut s = String::new();
+        io::stdin()
+            .read_to_string(&mut s)
+            .context("reading hash map from stdin")?;
+        s
+    } else {
+        fs::read_to_string(file).with_context(|| format!("reading {}", file.display()))?
+    };
+    let incoming: Mapping = serde_json::from_str(&txt).context("parsing imported hash map")?;
+
+    let mut entries = BTreeMap::new();
+    for (version, e) in incoming.map {
+        match to_sri(&e.hash) {
+            Some(sri) if is_valid_version(&version) => {
+                let entry = Entry {
+                    hash: sri,
+                    source: Source::Imported,
+                    ..e
+                };
+                entries.insert(version, entry);
+            }
+            _ => eprintln!("⚠️  Warning: skipping malformed entry {version} {}", e.hash),
+        }
+    }
+
+    let mut hs = HashStore::load().context("loading hash store")?;
+    let merge = hs.merge(entries)?;
+    if !merge.conflicts.is_empty() {
+        for c in &merge.conflicts {
+            eprintln!(
+                "conflict for {}: ours {} ({} store), theirs {}",
+                c.version, c.ours, c.layer, c.theirs
+            );
+        }
+        bail!(
+            "{} conflicting entries, so nothing was imported; `hash-store set` them first to accept the imported values",
+            merge.conflicts.len()
+        );
+    }
+    for version in &merge.added {
+        say!("Added {version}");
+    }
+    say!(
+        "Imported {} new, {} unchanged",
+        merge.added.len(),
+        merge.unchanged
+    );
+    report(
+        &json!({ "added": merge.added, "unchanged": merge.unchanged }),
+        || {},
+    );
+    Ok(())
+}
+
+fn is_valid_version(v: &str) -> bool {
+    Regex::new(r"^\d+(\.\d+)+$").unwrap().is_match(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "sha256-LUPmTFgb5e9VTemIixqpADfvbUX1QoTT2dztwI3E3CY=";
+    const B: &str = "sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU=";
+
+    fn mapping(entries: &[(&str, &str)]) -> Mapping {
+        Mapping {
+            map: entries
+                .iter()
+                .map(|(v, h)| {
+                    (
+                        v.to_string(),
+                        Entry::new(h.to_string(), Source::Manual, None),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn store(dir: &Path, entries: &[(&str, &str)]) -> HashStore {
+        let hs = HashStore {
+            data: mapping(entries),
+            path: dir.join("hashmap.json"),
+            layer: Layer::User,
+            fallback: vec![],
+        };
+        hs.persist().unwrap();
+        hs
+    }
+
+    #[test]
+    fn merge_reports_conflicts_without_overwriting() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut hs = store(tmp.path(), &[("570.133.07", A), ("550.54.14", A)]);
+        let incoming = mapping(&[("570.133.07", A), ("550.54.14", B), ("535.183.01", B)]);
+
+        let merge = hs.merge(incoming.map).unwrap();
+
+        assert_eq!(merge.added, ["535.183.01"]);
+        assert_eq!(merge.unchanged, 1);
+        // nothing is added while anything conflicts
+        assert_eq!(hs.get("535.183.01"), None);
+        assert_eq!(
+            merge.conflicts,
+            [Conflict {
+                version: "550.54.14".into(),
+                layer: Layer::User,
+                ours: A.into(),
+                theirs: B.into(),
+            }]
+        );
+        assert_eq!(hs.get("550.54.14"), Some(A));
+        assert_eq!(Mapping::read(&hs.path).unwrap().map.len(), 2);
+    }
+
+    #[test]
+    fn merge_reports_conflicts_with_read_only_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut hs = store(tmp.path(), &[]);
+        hs.fallback = vec![
+            (Layer::Global, mapping(&[("550.54.14", A)])),
+            (Layer::Embedded, mapping(&[("570.133.07", A)])),
+        ];
+        let incoming = mapping(&[("550.54.14", A), ("570.133.07", B)]);
+
+        let merge = hs.merge(incoming.map).unwrap();
+
+        assert!(merge.added.is_empty());
+        assert_eq!(merge.unchanged, 1);
+        assert_eq!(
+            merge.conflicts,
+            [Conflict {
+                version: "570.133.07".into(),
+                layer: Layer::Embedded,
+                ours: A.into(),
+                theirs: B.into(),
+            }]
+        );
+        // neither shadowed nor copied into the writable layer
+        assert!(Mapping::read(&hs.path).unwrap().map.is_empty());
+        assert_eq!(hs.get("570.133.07"), Some(A));
+    }
+
+    #[test]
+    fn export_includes_read_only_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut hs = store(tmp.path(), &[("570.133.07", A)]);
+        hs.fallback
+            .push((Layer::Embedded, mapping(&[("550.54.14", B)])));
+
+        let txt = serde_json::to_string(&json!({ "map": shown(&hs) })).unwrap();
+        let back: Mapping = serde_json::from_str(&txt).unwrap();
+        assert_eq!(back.map.len(), 2);
+        assert_eq!(back.map["550.54.14"].hash, B);
+        assert!(txt.contains(r#""layer":"embedded""#));
+    }
+
+    #[test]
+    fn prune_drops_malformed_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut hs = store(
+            tmp.path(),
+            &[
+                ("570.133.07", A),
+                ("latest", B),
+                ("550.54.14", "sha256-oops"),
+                (
+                    "535.183.01",
+                    "2d43e64c581be5ef554de9888b1aa90037ef6d45f54284d3d9dcedc08dc4dc26",
+                ),
+            ],
+        );
+
+        let pruned = hs.prune().unwrap();
+
+        assert_eq!(pruned.removed.len(), 2);
+        assert_eq!(pruned.normalized, ["535.183.01"]);
+        assert_eq!(hs.data.map.len(), 2);
+        // valid hex survives, rewritten as SRI
+        assert_eq!(hs.get("535.183.01"), Some(A));
+    }
+
+    #[test]
+    fn lookup_falls_through_layers_in_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut hs = store(tmp.path(), &[("570.133.07", A)]);
+        hs.fallback = vec![
+            (
+                Layer::Global,
+                mapping(&[("570.133.07", B), ("550.54.14", B)]),
+            ),
+            (
+                Layer::Embedded,
+                mapping(&[("550.54.14", A), ("535.183.01", A)]),
+            ),
+        ];
+
+        assert_eq!(hs.lookup("570.133.07").map(|(l, _)| l), Some(Layer::User));
+        assert_eq!(hs.lookup("550.54.14").map(|(l, _)| l), Some(Layer::Global));
+        assert_eq!(hs.get("550.54.14"), Some(B));
+        assert_eq!(
+            hs.lookup("535.183.01").map(|(l, _)| l),
+            Some(Layer::Embedded)
+        );
+        assert_eq!(hs.get("470.256.02"), None);
+        assert_eq!(hs.entries().len(), 3);
+    }
+
+    #[test]
+    fn legacy_plain_hash_entries_still_parse() {
+        let m: Mapping =
+            serde_json::from_str(&format!(r#"{{"map":{{"570.133.07":"{A}"}}}}"#)).unwrap();
+        let e = &m.map["570.133.07"];
+        assert_eq!(e.hash, A);
+        assert_eq!(e.source, Source::Unknown);
+        assert!(Mapping::embedded()
+            .map
+            .values()
+            .all(|e| e.source == Source::Embedded));
+    }
+
+    #[test]
+    fn embedded_hashes_are_per_architecture() {
+        assert!(Mapping::embedded_for("x86_64")
+            .map
+            .contains_key("570.133.07"));
+        // the x86_64 `.run` hash would be wrong for aarch64
+        assert!(!Mapping::embedded_for("aarch64")
+            .map
+            .contains_key("570.133.07"));
+        assert!(Mapping::embedded_for("riscv64").map.is_empty());
+    }
+}
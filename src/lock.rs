@@ -0,0 +1,157 @@
+use crate::error::Error;
+use anyhow::{Context, Result};
+use log::info;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// Held while a `sync` (or anything else that switches the farm) runs.
+pub const SYNC_LOCK: &str = "/var/lib/nix-opengl-driver/sync.lock";
+
+/// An advisory `flock(2)` lock, released when dropped.
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Take an exclusive lock on `path`, creating it (and its directory) if
+    /// needed. Blocks until any other holder lets go.
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        Self::acquire(path, libc::LOCK_EX)
+    }
+
+    /// Like [`FileLock::exclusive`], but `None` instead of waiting.
+    pub fn try_exclusive(path: &Path) -> io::Result<Option<Self>> {
+        let file = open(path)?;
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => Ok(Some(FileLock { _file: file })),
+            Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Take a shared lock on `path` for readers. Creates nothing: without
+    /// the lock file no writer has been here, so `None`.
+    pub fn shared(path: &Path) -> io::Result<Option<Self>> {
+        match File::open(path) {
+            Ok(file) => Self::lock(file, path, libc::LOCK_SH).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn acquire(path: &Path, op: libc::c_int) -> io::Result<Self> {
+        Self::lock(open(path)?, path, op)
+    }
+
+    fn lock(file: File, path: &Path, op: libc::c_int) -> io::Result<Self> {
+        if flock(&file, op | libc::LOCK_NB).is_err() {
+            info!("waiting for lock on {}", path.display());
+            flock(&file, op)?;
+        }
+        Ok(FileLock { _file: file })
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+fn flock(file: &File, op: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// `foo.json` → `foo.json.lock`
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".lock");
+    PathBuf::from(p)
+}
+
+/// Take the global sync lock so the boot service and a manual run can't race.
+/// Without `wait`, fail right away if another run holds it.
+pub fn sync_lock(wait: bool) -> Result<FileLock> {
+    let path = Path::new(SYNC_LOCK);
+    let lock = if wait {
+        FileLock::exclusive(path).map(Some)
+    } else {
+        FileLock::try_exclusive(path)
+    };
+    lock.with_context(|| format!("taking sync lock {}", SYNC_LOCK))?
+        .ok_or_else(|| Error::LockHeld(SYNC_LOCK.into()).into())
+}
+
+/// Crash-safe replacement of `path`: write a temp file next to it, fsync it,
+/// rename it over `path`, then fsync the directory so the rename is durable.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".tmp")
+        .tempfile_in(dir)?;
+    tmp.write_all(contents)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_atomic_replaces_without_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("state.json");
+        fs::write(&path, "old").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn exclusive_lock_excludes_other_holders() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("x.lock");
+        let held = FileLock::exclusive(&path).unwrap();
+
+        let other = File::open(&path).unwrap();
+        assert!(flock(&other, libc::LOCK_EX | libc::LOCK_NB).is_err());
+
+        drop(held);
+        assert!(flock(&other, libc::LOCK_EX | libc::LOCK_NB).is_ok());
+    }
+
+    #[test]
+    fn shared_lock_creates_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("missing").join("state.json.lock");
+        assert!(FileLock::shared(&path).unwrap().is_none());
+        assert!(!tmp.path().join("missing").exists());
+
+        let path = tmp.path().join("state.json.lock");
+        drop(FileLock::exclusive(&path).unwrap());
+        assert!(FileLock::shared(&path).unwrap().is_some());
+    }
+}
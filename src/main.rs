@@ -1,47 +1,92 @@
 mod build;
+mod check;
 mod cli;
+mod closure;
+mod config;
 mod detect;
+mod error;
+mod generations;
+mod hardware;
 mod hash_store;
+mod lock;
+mod metrics;
+mod nix_hash;
+mod nix_log;
+mod nix_probe;
+mod output;
+mod plan;
+mod process;
+mod profile;
+mod publish;
 mod service;
 mod state;
 mod tmpfiles;
 mod utils;
 
 use anyhow::Context as _;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
+use config::NixOptions;
 use detect::Driver;
-use log::info;
+use log::{info, warn};
+use output::{report, say, Done};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use utils::pin_store_path;
 
-fn main() -> Result<()> {
+fn main() {
     env_logger::init();
     let cli = cli::Cli::parse();
+    output::set_json(cli.output == cli::Output::Json);
 
-    match cli.cmd {
-        cli::Commands::Status => {
-            let d = pick_driver(&cli)?;
-            println!("Detected driver: {:?}", d);
-            if let Some(s) = state::State::load() {
-                println!("Active driver: {}", s.detected);
-                println!("Active path:   {}", s.active);
-                println!("Last sync:     {}", s.last_sync);
-            } else {
-                println!("Active driver: <none> (run `nix-opengl-driver sync`)");
-            }
+    if let Err(e) = run(&cli) {
+        let code = error::exit_code(&e);
+        report(&output::Failed::new(&e), || eprintln!("Error: {e:?}"));
+        std::process::exit(code);
+    }
+}
+
+fn run(cli: &cli::Cli) -> Result<()> {
+    match &cli.cmd {
+        cli::Commands::Status { verbose } => {
+            let d = pick_driver(cli)?;
+            let nix = nix_probe::NixInstall::get().describe();
+            let s = state::State::load();
+            let hardware_changes = s
+                .as_ref()
+                .and_then(|s| s.fingerprint.as_ref())
+                .map(|fp| fp.hardware.changes(&hardware::Hardware::probe()))
+                .unwrap_or_default();
+            let status = output::Status {
+                detected: &d,
+                nix,
+                state: s.as_ref(),
+                hardware_changes,
+            };
+            report(&status, || print_status(&status, *verbose));
         }
-        cli::Commands::Driver => {
-            println!("{:?}", pick_driver(&cli)?);
+        cli::Commands::Driver { export_profile } => {
+            let d = pick_driver(cli)?;
+            match export_profile {
+                Some(file) => {
+                    let profile = profile::Profile::detect(&d);
+                    profile.write(Some(file))?;
+                    // on stdout already unless it went to a file
+                    if file != Path::new("-") {
+                        report(&profile, || {});
+                    }
+                }
+                None => report(&d, || println!("{:?}", d)),
+            }
         }
         cli::Commands::Code => {
-            let d = pick_driver(&cli)?;
+            let d = pick_driver(cli)?;
             let nix_expr = if cli.resolve_hashes {
                 match &d {
                     Driver::Nvidia(_) => {
                         // two‐phase resolve
-                        let sha = build::resolve_hash(&d, cli.quiet)
+                        let sha = build::resolve_hash(&d, &cli.build_options()?)
                             .context("resolving NVIDIA hash for --resolve-hashes")?;
                         build::render_nix_expr(&d, Some(&sha))?
                     }
@@ -54,23 +99,111 @@ fn main() -> Result<()> {
                 // placeholders only
                 build::render_nix_expr(&d, None)?
             };
-            println!("{}", nix_expr);
-        }
-        cli::Commands::Build => {
-            let d = pick_driver(&cli)?;
-            let p = build::build_farm(&d, cli.quiet)?;
-            println!("{}", p.display());
-        }
-        cli::Commands::Sync => {
-            let d = pick_driver(&cli)?;
-            let p = build::build_farm(&d, cli.quiet)?;
-            info!("Updating GC root");
-            pin_store_path(&p.to_string_lossy(), state::GCROOT_SYMLINK)
-                .context("updating state file gc root")?;
-            state::State::save(&d, &p)?;
-            println!("Synced: {}", p.display());
-        }
-        cli::Commands::State => {
+            report(&serde_json::json!({ "expression": nix_expr }), || {
+                println!("{}", nix_expr)
+            });
+        }
+        cli::Commands::Build {
+            dry_run: true,
+            profile,
+        } => {
+            let d = build_driver(cli, profile.as_deref())?;
+            let plan = build::dry_run(&d, &cli.build_options()?)?;
+            let planned = output::Planned {
+                plan: &plan,
+                active: None,
+            };
+            report(&planned, || plan.print(false));
+        }
+        cli::Commands::Sync { dry_run: true, .. } => {
+            let d = pick_driver(cli)?;
+            let plan = build::dry_run(&d, &cli.build_options()?)?;
+            let planned = output::Planned {
+                plan: &plan,
+                active: state::active_farm(),
+            };
+            report(&planned, || plan.print(true));
+        }
+        cli::Commands::Build {
+            dry_run: false,
+            profile,
+        } => {
+            let d = build_driver(cli, profile.as_deref())?;
+            let opts = cli.build_options()?;
+            let p = build::build_farm(&d, &opts)?;
+            say!("{}", p.display());
+            let published = match cli.publish()? {
+                Some(to) => {
+                    publish::publish(&p, &to, &opts)?;
+                    Some(to.to)
+                }
+                None => None,
+            };
+            report(
+                &output::Built {
+                    driver: &d,
+                    path: p.display().to_string(),
+                    published,
+                },
+                || {},
+            );
+        }
+        cli::Commands::Sync {
+            dry_run: false,
+            diff,
+            if_changed,
+            fallback_mesa,
+            metrics_file,
+        } => {
+            let metrics_file = match metrics_file {
+                Some(f) => Some(f.clone()),
+                None => config::Config::load()?.metrics_file,
+            };
+            // every failure below must reach the metrics, not only the build's
+            let mut detected = None;
+            let mut nix = NixOptions::default();
+            let result = (|| {
+                let _lock = lock::sync_lock(!cli.no_wait)?;
+                let d = detected.insert(pick_driver(cli)?);
+                let opts = cli.build_options()?;
+                nix = opts.nix.clone();
+                sync(cli, d, &opts, *diff, *if_changed, *fallback_mesa)
+            })();
+            if let Some(file) = metrics_file {
+                if let Err(e) = metrics::write(&file, detected.as_ref(), &nix, result.is_err()) {
+                    eprintln!("⚠️  Warning: {e:#}");
+                }
+            }
+            result?;
+        }
+        cli::Commands::Metrics { file } => {
+            let d = pick_driver(cli).map_err(|e| warn!("{e:#}")).ok();
+            let nix = cli.build_options()?.nix;
+            match file {
+                Some(f) => {
+                    metrics::write(f, d.as_ref(), &nix, false)?;
+                    report(&serde_json::json!({ "written": f }), || {});
+                }
+                None => {
+                    let text = metrics::collect(d.as_ref(), &nix, false);
+                    report(&serde_json::json!({ "metrics": text }), || print!("{text}"));
+                }
+            }
+        }
+        cli::Commands::Diff => {
+            let d = pick_driver(cli)?;
+            let opts = cli.build_options()?;
+            let p = build::build_farm(&d, &opts)?;
+            let diff = farm_diff(&p, &opts.nix)?;
+            report(&diff, || diff.print());
+        }
+        cli::Commands::State {
+            cmd: Some(cli::StateCommand::Repair),
+        } => {
+            let _lock = lock::sync_lock(!cli.no_wait)?;
+            state::repair()?;
+        }
+        cli::Commands::State { .. } => {
             // Prefer the primary state file, otherwise the backup
             let path = if Path::new(state::STATE_FILE).exists() {
                 state::STATE_FILE
@@ -78,42 +211,50 @@ fn main() -> Result<()> {
                 eprintln!("primary state missing, reading backup");
                 state::STATE_BAK
             } else {
-                eprintln!(
+                bail!(
                     "no state file found at {} or {}",
                     state::STATE_FILE,
                     state::STATE_BAK
                 );
-                std::process::exit(1);
             };
 
-            let data = fs::read_to_string(path).unwrap_or_else(|e| {
-                eprintln!("failed to read {}: {}", path, e);
-                std::process::exit(1);
-            });
-            println!("{}", data);
+            let data = fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+            match serde_json::from_str::<serde_json::Value>(&data) {
+                Ok(v) => report(&v, || println!("{}", data)),
+                Err(_) => report(&serde_json::json!({ "raw": data }), || println!("{}", data)),
+            }
+        }
+        cli::Commands::Check => {
+            let health = check::run(pick_driver(cli));
+            report(&health, || health.print());
+            std::process::exit(health.status.exit_code());
         }
         cli::Commands::Tmpfiles => {
             tmpfiles::print_rule();
         }
         cli::Commands::TmpfilesInstall => {
             tmpfiles::install_rule()?;
-            println!("Installed tmpfiles.d rule and populated /run/opengl-driver");
+            Done::report("Installed tmpfiles.d rule and populated /run/opengl-driver");
         }
         cli::Commands::TmpfilesUninstall => {
             tmpfiles::uninstall_rule().context("uninstalling tmpfiles rule")?;
-            println!("Uninstalled tmpfiles rule");
+            Done::report("Uninstalled tmpfiles rule");
         }
         cli::Commands::Service => service::print_service().context("printing systemd service")?,
         cli::Commands::ServiceInstall => {
             service::install_service(cli.quiet).context("installing systemd service")?;
+            Done::json_only("Installed and enabled the sync service");
         }
         cli::Commands::ServiceUninstall => {
             service::uninstall_service().context("uninstalling systemd service")?;
+            Done::json_only("Uninstalled the sync service");
         }
         cli::Commands::Install => {
             tmpfiles::install_rule().context("installing tmpfiles rule")?;
             service::install_service(cli.quiet).context("installing systemd service")?;
-            println!("Installed tmpfiles.d rule, service file and populated /run/opengl-driver");
+            Done::report(
+                "Installed tmpfiles.d rule, service file and populated /run/opengl-driver",
+            );
         }
         cli::Commands::Uninstall => {
             use std::io::ErrorKind;
@@ -125,31 +266,336 @@ fn main() -> Result<()> {
                     Err(e) => match e.kind() {
                         ErrorKind::NotFound => continue,
                         ErrorKind::PermissionDenied => {
-                            eprintln!("Failed to remove {}: {}", path, e);
-                            std::process::exit(1);
+                            return Err(e).context(error::Error::PermissionDenied(format!(
+                                "removing {path}; run as root"
+                            )));
                         }
                         _ => return Err(e.into()),
                     },
                 }
             }
+            generations::remove_all()?;
             service::uninstall_service().context("uninstalling systemd service")?;
             tmpfiles::uninstall_rule().context("uninstalling tmpfiles rule")?;
-            println!("Uninstalled gc-root, state, tmpfiles rule and service");
+            Done::report("Uninstalled gc-roots, state, tmpfiles rule and service");
         }
-        cli::Commands::HashStore => {
-            hash_store::print_store().context("printing hash store")?;
+        cli::Commands::Generations { cmd } => match cmd {
+            None | Some(cli::GenerationsCommand::List) => generations::list()?,
+            Some(cli::GenerationsCommand::Delete { older_than, keep }) => {
+                let _lock = lock::sync_lock(!cli.no_wait)?;
+                generations::delete(*older_than, *keep)?;
+            }
+        },
+        cli::Commands::Rollback { generation } => {
+            let _lock = lock::sync_lock(!cli.no_wait)?;
+            generations::rollback(*generation)?;
+        }
+        cli::Commands::HashStore { cmd } => {
+            use cli::HashStoreCommand as H;
+            match cmd {
+                None => hash_store::print_store().context("printing hash store")?,
+                Some(H::List) => hash_store::list_entries()?,
+                Some(H::Get { version }) => hash_store::get_entry(version)?,
+                Some(H::Set { version, hash }) => hash_store::set_entry(version, hash)?,
+                Some(H::Remove { version }) => hash_store::remove_entry(version)?,
+                Some(H::Prune) => hash_store::prune_entries()?,
+                Some(H::Export { file }) => hash_store::export(file.as_deref())?,
+                Some(H::Import { file }) => hash_store::import(file)?,
+            }
         }
     }
 
     Ok(())
 }
 
+/// Build and switch to the farm for `d`; a failure is recorded in the state.
+fn sync(
+    cli: &cli::Cli,
+    d: &Driver,
+    opts: &build::BuildOptions,
+    diff: bool,
+    if_changed: bool,
+    fallback_mesa: bool,
+) -> Result<()> {
+    if if_changed {
+        if let Some(fp) = build::fingerprint(d, opts)? {
+            if state::is_current(&fp) {
+                let active = state::active_farm().unwrap_or_default();
+                let synced = output::Synced {
+                    driver: d,
+                    path: active,
+                    changed: false,
+                    generation: None,
+                    published: None,
+                };
+                report(&synced, || println!("Up to date: {}", synced.path));
+                return Ok(());
+            }
+            if let Some(old) = state::State::load().and_then(|s| s.fingerprint) {
+                for c in old.changes(&fp) {
+                    info!("{c}");
+                }
+            }
+        }
+    }
+    let active = state::State::load().map(|s| s.detected);
+    let (p, generation) = switch_or_fall_back(
+        d,
+        fallback_mesa,
+        active.as_deref(),
+        |to, fallback| switch_to(to, opts, diff && !fallback),
+        |d, e| {
+            if let Err(se) = state::State::record_failure(d, e) {
+                eprintln!("⚠️  Warning: could not record the failure: {se}");
+            }
+        },
+    )?;
+    say!("Synced: {}", p.display());
+    // the switch already happened; a cache that's down doesn't undo it
+    let published = match cli.publish().and_then(|to| {
+        to.map(|to| publish::publish(&p, &to, opts).map(|()| to.to))
+            .transpose()
+    }) {
+        Ok(published) => published,
+        Err(e) => {
+            eprintln!("⚠️  Warning: synced, but publishing failed: {e:#}");
+            None
+        }
+    };
+    let synced = output::Synced {
+        driver: d,
+        path: p.display().to_string(),
+        changed: true,
+        generation: Some(generation),
+        published,
+    };
+    report(&synced, || {});
+    Ok(())
+}
+
+/// Switch to `d` with `switch`. If that fails, the previous generation stays
+/// active; when it was synced for another driver (`active`) it won't work
+/// with the loaded NVIDIA module, so `fallback_mesa` switches to Mesa
+/// instead. `switch` is told whether it builds the fallback. Either way the
+/// failure for `d` goes to `record` and is returned.
+fn switch_or_fall_back(
+    d: &Driver,
+    fallback_mesa: bool,
+    active: Option<&str>,
+    mut switch: impl FnMut(&Driver, bool) -> Result<(PathBuf, u32)>,
+    record: impl FnOnce(&Driver, &anyhow::Error),
+) -> Result<(PathBuf, u32)> {
+    let e = match switch(d, false) {
+        Ok(switched) => return Ok(switched),
+        Err(e) => e,
+    };
+    let stale = active.is_none_or(|a| a != d.to_string());
+    if fallback_mesa && matches!(d, Driver::Nvidia(_)) && stale {
+        eprintln!("⚠️  Warning: NVIDIA farm failed to build, falling back to Mesa");
+        match switch(&Driver::Mesa, true) {
+            Ok((p, _)) => say!("Synced Mesa fallback: {}", p.display()),
+            Err(fe) => eprintln!("⚠️  Warning: Mesa fallback failed too: {fe:#}"),
+        }
+    }
+    record(d, &e);
+    Err(e)
+}
+
+/// Build the farm for `d` and make it the active generation. Nothing is
+/// switched unless the build succeeds.
+fn switch_to(d: &Driver, opts: &build::BuildOptions, diff: bool) -> Result<(PathBuf, u32)> {
+    let started = Instant::now();
+    let p = build::build_farm(d, opts)?;
+    let took = started.elapsed();
+    if diff {
+        // only informational: a collected or missing old farm mustn't stop the switch
+        match farm_diff(&p, &opts.nix) {
+            Ok(diff) => diff.print(),
+            Err(e) => eprintln!("⚠️  Warning: could not diff the closures: {e:#}"),
+        }
+    }
+    // the hash is resolved now, so this is the farm's real fingerprint
+    let fp = build::fingerprint(d, opts)?;
+    let provenance = build::provenance(d, opts, &p, fp.as_ref(), took)
+        .map_err(|e| warn!("could not record provenance: {e:#}"))
+        .ok();
+    let gen = generations::add(&p, d, fp.clone(), provenance.clone())?;
+    info!("Switching to generation {gen}");
+    pin_store_path(&p.to_string_lossy(), state::GCROOT_SYMLINK)
+        .context("updating state file gc root")?;
+    state::State::save(d, &p, fp, provenance)?;
+    Ok((p, gen))
+}
+
+/// Compare `candidate`'s closure with the active farm's.
+fn farm_diff(candidate: &Path, nix: &NixOptions) -> Result<closure::Diff> {
+    let new =
+        closure::closure(&candidate.to_string_lossy(), nix).context("querying new closure")?;
+    let active = state::active_farm();
+    let old = match &active {
+        Some(active) => closure::closure(active, nix).context("querying active closure")?,
+        None => Vec::new(),
+    };
+    Ok(closure::Diff::new(
+        active,
+        candidate.display().to_string(),
+        &old,
+        &new,
+    ))
+}
+
+fn print_status(status: &output::Status, verbose: bool) {
+    println!("Detected driver: {:?}", status.detected);
+    println!("Nix:             {}", status.nix);
+    let Some(s) = status.state else {
+        println!("Active driver: <none> (run `nix-opengl-driver sync`)");
+        return;
+    };
+    println!("Active driver: {}", s.detected);
+    println!("Active path:   {}", s.active);
+    println!("Last sync:     {}", s.last_sync);
+    if let (true, Some(p)) = (verbose, &s.provenance) {
+        println!("Tool version:  {}", p.tool_version);
+        println!("Expression:    {}", p.expr);
+        println!(
+            "nixpkgs:       {}",
+            p.nixpkgs.as_deref().unwrap_or("<unknown>")
+        );
+        if let Some(h) = &p.nvidia_hash {
+            println!("NVIDIA hash:   {h}");
+        }
+        println!("Build time:    {:.1}s", p.build_seconds);
+        println!("Packages:");
+        for (name, version) in &p.packages {
+            println!("  {name} {version}");
+        }
+    }
+    if let Some(f) = &s.last_failure {
+        println!("Last failure:  {} ({})", f.time, f.driver);
+        for line in f.error.lines() {
+            println!("  {line}");
+        }
+    }
+    if s.fingerprint.is_some() {
+        if status.hardware_changes.is_empty() {
+            println!("Hardware:      unchanged since last sync");
+        } else {
+            println!("Hardware:      changed since last sync, next sync rebuilds");
+            for c in &status.hardware_changes {
+                println!("  {c}");
+            }
+        }
+    }
+}
+
+/// The driver from `--profile` if given, else this machine's.
+fn build_driver(cli: &cli::Cli, profile: Option<&Path>) -> Result<Driver> {
+    match profile {
+        Some(p) => profile::Profile::read(p)?.driver(),
+        None => pick_driver(cli),
+    }
+}
+
 fn pick_driver(cli: &cli::Cli) -> Result<Driver, anyhow::Error> {
     if let Some(ver) = &cli.force_nvidia {
         Ok(Driver::Nvidia(ver.clone()))
     } else if cli.force_mesa {
         Ok(Driver::Mesa)
     } else {
-        detect::detect_driver()
+        detect::detect_driver().context(error::Error::DetectionFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::RefCell;
+
+    const NVIDIA: &str = "nvidia 575.51.02";
+
+    /// State after a sync for `detected`, and the drivers tried so far.
+    fn setup(detected: &str) -> (RefCell<state::State>, RefCell<Vec<String>>) {
+        let s = state::State {
+            detected: detected.into(),
+            active: "/nix/store/old-farm".into(),
+            ..state::State::default()
+        };
+        (RefCell::new(s), RefCell::new(vec![]))
+    }
+
+    /// Sync `d` with a `switch` that fails for NVIDIA and otherwise saves
+    /// like [`switch_to`] does.
+    fn run(
+        s: &RefCell<state::State>,
+        tried: &RefCell<Vec<String>>,
+        d: &Driver,
+        fallback_mesa: bool,
+    ) -> Result<(PathBuf, u32)> {
+        let active = s.borrow().detected.clone();
+        switch_or_fall_back(
+            d,
+            fallback_mesa,
+            Some(&active),
+            |to, _| {
+                tried.borrow_mut().push(to.to_string());
+                if let Driver::Nvidia(_) = to {
+                    return Err(anyhow!("`nix build` failed").context(error::Error::BuildFailed));
+                }
+                let mut s = s.borrow_mut();
+                s.detected = to.to_string();
+                s.active = "/nix/store/mesa-farm".into();
+                s.last_failure = None;
+                Ok(("/nix/store/mesa-farm".into(), 2))
+            },
+            |d, e| s.borrow_mut().failed(d, e),
+        )
+    }
+
+    #[test]
+    fn a_failed_build_keeps_the_previous_generation() {
+        let (s, tried) = setup("nvidia 570.133.07");
+        let d = Driver::Nvidia("575.51.02".into());
+
+        let e = run(&s, &tried, &d, false).unwrap_err();
+
+        assert_eq!(error::Kind::of(&e), error::Kind::BuildFailed);
+        assert_eq!(*tried.borrow(), [NVIDIA]);
+        let s = s.borrow();
+        assert_eq!(s.active, "/nix/store/old-farm");
+        let failure = s.last_failure.as_ref().unwrap();
+        assert_eq!(failure.driver, NVIDIA);
+        assert!(failure.error.contains("`nix build` failed"));
+    }
+
+    #[test]
+    fn falls_back_to_mesa_but_still_fails() {
+        let (s, tried) = setup("nvidia 570.133.07");
+        let d = Driver::Nvidia("575.51.02".into());
+
+        let e = run(&s, &tried, &d, true).unwrap_err();
+
+        // the NVIDIA error, not the fallback's success
+        assert_eq!(error::Kind::of(&e), error::Kind::BuildFailed);
+        assert_eq!(*tried.borrow(), [NVIDIA, "mesa"]);
+        let s = s.borrow();
+        assert_eq!(s.active, "/nix/store/mesa-farm");
+        assert_eq!(s.last_failure.as_ref().unwrap().driver, NVIDIA);
+    }
+
+    #[test]
+    fn no_fallback_while_the_active_farm_fits() {
+        // a retry of the driver the active farm was already built for
+        let (s, tried) = setup(NVIDIA);
+        let d = Driver::Nvidia("575.51.02".into());
+        assert!(run(&s, &tried, &d, true).is_err());
+        assert_eq!(*tried.borrow(), [NVIDIA]);
+        assert_eq!(s.borrow().active, "/nix/store/old-farm");
+
+        // Mesa itself succeeding records nothing
+        let (s, tried) = setup(NVIDIA);
+        assert!(run(&s, &tried, &Driver::Mesa, true).is_ok());
+        assert_eq!(*tried.borrow(), ["mesa"]);
+        assert!(s.borrow().last_failure.is_none());
     }
 }
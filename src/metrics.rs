@@ -0,0 +1,260 @@
+//! Prometheus metrics in the node_exporter textfile-collector format.
+
+use crate::check;
+use crate::closure;
+use crate::config::NixOptions;
+use crate::detect::Driver;
+use crate::lock::write_atomic;
+use crate::state::{self, State, GCROOT_SYMLINK};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::{fmt::Write as _, fs, path::Path};
+
+/// Append one metric with its help and type lines.
+fn metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    labels: &str,
+    value: impl ToString,
+) {
+    let _ = writeln!(out, "# HELP nix_opengl_driver_{name} {help}");
+    let _ = writeln!(out, "# TYPE nix_opengl_driver_{name} {kind}");
+    let _ = writeln!(
+        out,
+        "nix_opengl_driver_{name}{labels} {}",
+        value.to_string()
+    );
+}
+
+/// `nvidia 570.133.07` → `{driver="nvidia",version="570.133.07"}`
+fn driver_labels(driver: &str) -> String {
+    let (name, version) = driver.split_once(' ').unwrap_or((driver, ""));
+    let escape = |s: &str| {
+        s.replace('\\', r"\\")
+            .replace('"', r#"\""#)
+            .replace('\n', r"\n")
+    };
+    format!(
+        r#"{{driver="{}",version="{}"}}"#,
+        escape(name),
+        escape(version)
+    )
+}
+
+fn timestamp(rfc3339: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(rfc3339)
+        .ok()
+        .map(|t| t.timestamp())
+}
+
+/// `farm` is the driver the active farm was built for and `closure_size`
+/// its closure's size in bytes, when they could be found out. `failed` is
+/// set when the sync writing them just failed, possibly before it got far
+/// enough to record that in the state.
+fn render(
+    s: Option<&State>,
+    detected: Option<&Driver>,
+    farm: Option<&str>,
+    closure_size: Option<u64>,
+    failed: Option<DateTime<Utc>>,
+) -> String {
+    let mut out = String::new();
+    if let Some(d) = detected {
+        metric(
+            &mut out,
+            "detected_info",
+            "Driver loaded by the kernel.",
+            "gauge",
+            &driver_labels(&d.to_string()),
+            1,
+        );
+    }
+    let in_sync = match (s, detected) {
+        (Some(s), Some(d)) => check::state_matches(Some(s), farm, d).is_none(),
+        _ => false,
+    };
+    metric(
+        &mut out,
+        "in_sync",
+        "Whether the active farm matches the loaded driver.",
+        "gauge",
+        "",
+        u8::from(in_sync),
+    );
+    // a successful sync clears the recorded failure
+    let last_failure = failed
+        .map(|t| t.timestamp())
+        .or_else(|| s?.last_failure.as_ref().and_then(|f| timestamp(&f.time)));
+    if s.is_some() || last_failure.is_some() {
+        metric(
+            &mut out,
+            "last_sync_success",
+            "Whether the most recent sync succeeded.",
+            "gauge",
+            "",
+            u8::from(last_failure.is_none()),
+        );
+    }
+    if let Some(t) = last_failure {
+        metric(
+            &mut out,
+            "last_failure_timestamp_seconds",
+            "When the most recent sync failed.",
+            "gauge",
+            "",
+            t,
+        );
+    }
+    let Some(s) = s else {
+        return out;
+    };
+
+    metric(
+        &mut out,
+        "active_info",
+        "Driver the active farm was synced for.",
+        "gauge",
+        &driver_labels(&s.detected),
+        1,
+    );
+    if let Some(t) = timestamp(&s.last_sync) {
+        metric(
+            &mut out,
+            "last_sync_timestamp_seconds",
+            "When the active farm was last synced.",
+            "gauge",
+            "",
+            t,
+        );
+    }
+    if let Some(p) = &s.provenance {
+        metric(
+            &mut out,
+            "build_duration_seconds",
+            "How long building the active farm took.",
+            "gauge",
+            "",
+            p.build_seconds,
+        );
+    }
+    metric(
+        &mut out,
+        "generations",
+        "Number of generations kept for rollback.",
+        "gauge",
+        "",
+        s.generations.len(),
+    );
+    if let Some(size) = closure_size {
+        metric(
+            &mut out,
+            "closure_size_bytes",
+            "Size of the active farm's closure.",
+            "gauge",
+            "",
+            size,
+        );
+    }
+    out
+}
+
+/// Metrics for this machine; `detected` is the driver the kernel has loaded.
+/// `failed` is set by a sync that failed, see [`render`].
+pub fn collect(detected: Option<&Driver>, nix: &NixOptions, failed: bool) -> String {
+    let target = fs::read_link(GCROOT_SYMLINK).ok().filter(|t| t.exists());
+    let farm = target.as_deref().map(state::farm_driver);
+    let closure_size = target.as_deref().and_then(|t| {
+        closure::closure(&t.to_string_lossy(), nix)
+            .ok()
+            .map(|c| c.iter().map(|(_, size)| size).sum())
+    });
+    render(
+        State::load().as_ref(),
+        detected,
+        farm.as_deref(),
+        closure_size,
+        failed.then(Utc::now),
+    )
+}
+
+/// Replace `file` atomically, so the collector never reads half of it.
+pub fn write(file: &Path, detected: Option<&Driver>, nix: &NixOptions, failed: bool) -> Result<()> {
+    write_atomic(file, collect(detected, nix, failed).as_bytes())
+        .with_context(|| format!("writing metrics to {}", file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Failure;
+
+    #[test]
+    fn renders_textfile_metrics() {
+        let nvidia = Driver::Nvidia("570.133.07".into());
+        let mut s = State {
+            detected: "nvidia 570.133.07".into(),
+            last_sync: "2025-05-01T08:00:00+00:00".into(),
+            ..State::default()
+        };
+        let out = render(
+            Some(&s),
+            Some(&nvidia),
+            Some("nvidia 570.133.07"),
+            Some(42),
+            None,
+        );
+        for line in [
+            r#"nix_opengl_driver_active_info{driver="nvidia",version="570.133.07"} 1"#,
+            "nix_opengl_driver_in_sync 1",
+            "nix_opengl_driver_last_sync_timestamp_seconds 1746086400",
+            "nix_opengl_driver_last_sync_success 1",
+            "nix_opengl_driver_generations 0",
+            "nix_opengl_driver_closure_size_bytes 42",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line} in\n{out}");
+        }
+
+        s.last_failure = Some(Failure {
+            time: "2025-05-02T08:00:00+00:00".into(),
+            driver: "nvidia 575.51.02".into(),
+            error: "boom".into(),
+        });
+        let newer = Driver::Nvidia("575.51.02".into());
+        let out = render(
+            Some(&s),
+            Some(&newer),
+            Some("nvidia 570.133.07"),
+            None,
+            None,
+        );
+        assert!(out.contains("nix_opengl_driver_in_sync 0\n"));
+        assert!(out.contains("nix_opengl_driver_last_sync_success 0\n"));
+        assert!(out.contains("nix_opengl_driver_last_failure_timestamp_seconds 1746172800\n"));
+        assert!(!out.contains("closure_size_bytes"));
+    }
+
+    #[test]
+    fn reports_failures_the_state_never_saw() {
+        let now = DateTime::parse_from_rfc3339("2025-05-03T08:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        // detection failed on the first sync: no driver, no state
+        let out = render(None, None, None, None, Some(now));
+        assert!(out.contains("nix_opengl_driver_in_sync 0\n"));
+        assert!(out.contains("nix_opengl_driver_last_sync_success 0\n"));
+        assert!(out.contains("nix_opengl_driver_last_failure_timestamp_seconds 1746259200\n"));
+
+        // another sync held the lock; the state still says all is well
+        let s = State {
+            detected: "mesa".into(),
+            ..State::default()
+        };
+        let out = render(Some(&s), Some(&Driver::Mesa), None, None, Some(now));
+        assert!(out.contains("nix_opengl_driver_last_sync_success 0\n"));
+        let out = render(Some(&s), Some(&Driver::Mesa), None, None, None);
+        assert!(out.contains("nix_opengl_driver_last_sync_success 1\n"));
+        assert!(!out.contains("last_failure_timestamp_seconds"));
+    }
+}
@@ -0,0 +1,292 @@
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+/// Nix's base32 alphabet (no `e`, `o`, `t`, `u`).
+const NIX_BASE32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Supported hash algorithms and their digest sizes in bytes.
+const ALGOS: &[(&str, usize)] = &[("md5", 16), ("sha1", 20), ("sha256", 32), ("sha512", 64)];
+
+/// One fixed-output hash mismatch reported by a Nix build.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashMismatch {
+    /// Store path of the failing derivation (or its output, on older Nix).
+    pub path: String,
+    /// Derivation name, i.e. `path` without store hash and `.drv` suffix.
+    pub name: String,
+    /// The hash the expression specified, as SRI, if reported.
+    pub specified: Option<String>,
+    /// The hash Nix actually got, as SRI.
+    pub got: String,
+}
+
+/// Convert any hash representation Nix prints to SRI (`sha256-<base64>`).
+///
+/// Accepts SRI, `algo:<digest>` and bare digests in hex, Nix base32 or
+/// base64. Bare digests are assumed to be sha256.
+pub fn to_sri(hash: &str) -> Option<String> {
+    let hash = hash.trim();
+    let (algo, digest) = if let Some((a, d)) = hash.split_once('-').filter(|(a, _)| is_algo(a)) {
+        (a, d)
+    } else if let Some((a, d)) = hash.split_once(':').filter(|(a, _)| is_algo(a)) {
+        (a, d)
+    } else {
+        ("sha256", hash)
+    };
+    let size = ALGOS.iter().find(|(a, _)| *a == algo)?.1;
+
+    let bytes = if digest.len() == size * 2 {
+        decode_hex(digest)?
+    } else if digest.len() == base32_len(size) {
+        decode_base32(digest, size)?
+    } else if digest.len() == size.div_ceil(3) * 4 {
+        decode_base64(digest)?
+    } else {
+        return None;
+    };
+    if bytes.len() != size {
+        return None;
+    }
+    Some(format!("{}-{}", algo, encode_base64(&bytes)))
+}
+
+/// SRI sha256 of `data`, e.g. to fingerprint a rendered expression.
+pub fn sha256_sri(data: &[u8]) -> String {
+    format!("sha256-{}", encode_base64(&Sha256::digest(data)))
+}
+
+/// Find every fixed-output hash mismatch in Nix's stderr.
+///
+/// Understands Nix 1.x, 2.0–2.3, current Nix, Lix and Determinate Nix
+/// output, with or without ANSI colours.
+pub fn parse_mismatches(stderr: &str) -> Vec<HashMismatch> {
+    let ansi = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+    let text = ansi.replace_all(stderr, "");
+
+    let q = r"['‘’]";
+    let header = Regex::new(&format!(
+        r"hash mismatch in fixed-output derivation {q}([^'‘’]+){q}"
+    ))
+    .unwrap();
+    let specified = Regex::new(r"^\s*(?:specified|wanted):\s*(\S+)").unwrap();
+    let got = Regex::new(r"^\s*got:\s*(\S+)").unwrap();
+    // Nix 2.0–2.1
+    let produced = Regex::new(&format!(
+        r"fixed-output derivation produced path {q}([^'‘’]+){q} with (\w+) hash {q}([^'‘’]+){q} instead of the expected hash {q}([^'‘’]+){q}"
+    ))
+    .unwrap();
+    // Nix 1.x
+    let output_has = Regex::new(&format!(
+        r"output path {q}([^'‘’]+){q} has (\w+) hash {q}([^'‘’]+){q} when {q}([^'‘’]+){q} was expected"
+    ))
+    .unwrap();
+
+    let mut found = Vec::new();
+    let mut pending: Option<(String, Option<String>)> = None;
+
+    for line in text.lines() {
+        if let Some(c) = header.captures(line) {
+            pending = Some((c[1].to_string(), None));
+        } else if let Some(c) = produced
+            .captures(line)
+            .or_else(|| output_has.captures(line))
+        {
+            let algo = &c[2];
+            let got = to_sri(&format!("{algo}:{}", &c[3]));
+            if let Some(got) = got {
+                found.push(HashMismatch {
+                    path: c[1].to_string(),
+                    name: drv_name(&c[1]),
+                    specified: to_sri(&format!("{algo}:{}", &c[4])),
+                    got,
+                });
+            }
+        } else if let Some((_, spec)) = pending.as_mut() {
+            if let Some(c) = specified.captures(line) {
+                *spec = to_sri(&c[1]);
+            } else if let Some(c) = got.captures(line) {
+                let (path, spec) = pending.take().unwrap();
+                if let Some(got) = to_sri(&c[1]) {
+                    found.push(HashMismatch {
+                        name: drv_name(&path),
+                        path,
+                        specified: spec,
+                        got,
+                    });
+                }
+            }
+        }
+    }
+
+    found
+}
+
+/// `/nix/store/<hash>-foo-1.0.drv` → `foo-1.0`
+fn drv_name(path: &str) -> String {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let base = base.strip_suffix(".drv").unwrap_or(base);
+    match base.split_once('-') {
+        Some((hash, rest)) if hash.len() == 32 => rest.to_string(),
+        _ => base.to_string(),
+    }
+}
+
+fn is_algo(s: &str) -> bool {
+    ALGOS.iter().any(|(a, _)| *a == s)
+}
+
+fn base32_len(size: usize) -> usize {
+    (size * 8 - 1) / 5 + 1
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_base32(s: &str, size: usize) -> Option<Vec<u8>> {
+    let mut out = vec![0u8; size];
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = NIX_BASE32.iter().position(|&x| x == c)? as u16;
+        let b = n * 5;
+        let (i, j) = (b / 8, b % 8);
+        let v = digit << j;
+        out[i] |= v as u8;
+        let carry = (v >> 8) as u8;
+        if i + 1 < size {
+            out[i + 1] |= carry;
+        } else if carry != 0 {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        acc = (acc << 6) | BASE64.iter().position(|&x| x == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NVIDIA_SRI: &str = "sha256-LUPmTFgb5e9VTemIixqpADfvbUX1QoTT2dztwI3E3CY=";
+    const NVIDIA_NAME: &str = "NVIDIA-Linux-x86_64-570.133.07.run";
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_str!(concat!("../tests/fixtures/hash-mismatch/", $name))
+        };
+    }
+
+    fn single(out: &str) -> HashMismatch {
+        let found = parse_mismatches(out);
+        assert_eq!(found.len(), 1, "{found:?}");
+        found.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn sri_from_every_encoding() {
+        let hex = "2d43e64c581be5ef554de9888b1aa90037ef6d45f54284d3d9dcedc08dc4dc26";
+        let b32 = "09nwqj6w1vfwv79q8hpm8mnyydq0m4d8p2799mayzr8vb16fchrd";
+        for h in [
+            NVIDIA_SRI.to_string(),
+            format!("sha256:{hex}"),
+            format!("sha256:{b32}"),
+            hex.to_string(),
+            b32.to_string(),
+        ] {
+            assert_eq!(to_sri(&h).as_deref(), Some(NVIDIA_SRI), "{h}");
+        }
+        assert_eq!(to_sri("sha256:nope"), None);
+        assert_eq!(
+            sha256_sri(b""),
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
+
+    #[test]
+    fn nix_1_11() {
+        let m = single(fixture!("nix-1.11.txt"));
+        assert_eq!(m.name, NVIDIA_NAME);
+        assert_eq!(m.got, NVIDIA_SRI);
+        assert_eq!(
+            m.specified.as_deref(),
+            Some("sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")
+        );
+    }
+
+    #[test]
+    fn nix_2_0() {
+        let m = single(fixture!("nix-2.0.txt"));
+        assert_eq!(m.name, NVIDIA_NAME);
+        assert_eq!(m.got, NVIDIA_SRI);
+    }
+
+    #[test]
+    fn nix_2_3() {
+        let m = single(fixture!("nix-2.3.txt"));
+        assert_eq!(m.name, NVIDIA_NAME);
+        assert_eq!(m.got, NVIDIA_SRI);
+        assert!(m.specified.is_some());
+    }
+
+    #[test]
+    fn nix_2_24_multiple() {
+        let found = parse_mismatches(fixture!("nix-2.24.txt"));
+        let names: Vec<_> = found.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["SRB5_1_linux64.zip", NVIDIA_NAME]);
+        assert_eq!(
+            found[0].got,
+            "sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU="
+        );
+        assert_eq!(found[1].got, NVIDIA_SRI);
+    }
+
+    #[test]
+    fn lix_2_91() {
+        let m = single(fixture!("lix-2.91.txt"));
+        assert_eq!(m.name, NVIDIA_NAME);
+        assert_eq!(m.got, NVIDIA_SRI);
+    }
+
+    #[test]
+    fn determinate_3() {
+        let m = single(fixture!("determinate-3.txt"));
+        assert_eq!(m.name, NVIDIA_NAME);
+        assert_eq!(m.got, NVIDIA_SRI);
+    }
+}
@@ -0,0 +1,416 @@
+use crate::nix_hash::{self, HashMismatch};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{self, IsTerminal, Write},
+};
+
+const PREFIX: &str = "@nix ";
+
+// Activity types (nix/src/libutil/logging.hh)
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const ACT_SUBSTITUTE: u64 = 108;
+
+// Result types
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_SET_PHASE: u64 = 104;
+const RES_PROGRESS: u64 = 105;
+const RES_SET_EXPECTED: u64 = 106;
+
+// Verbosity levels
+const LVL_ERROR: u64 = 0;
+const LVL_WARN: u64 = 1;
+
+/// How to show Nix's progress on stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Progress {
+    /// A status line on a terminal, plain lines otherwise
+    #[default]
+    Auto,
+    /// A single, continuously updated status line
+    Bar,
+    /// One line per started build or download
+    Plain,
+    /// One JSON object per event (machine-readable)
+    Json,
+    /// Nothing
+    None,
+}
+
+impl Progress {
+    fn resolve(self) -> Self {
+        match self {
+            Progress::Auto if io::stderr().is_terminal() => Progress::Bar,
+            Progress::Auto => Progress::Plain,
+            p => p,
+        }
+    }
+}
+
+/// What kind of work an activity represents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActivityKind {
+    Build,
+    Builds,
+    Download,
+    Substitute,
+    CopyPaths,
+    Other,
+}
+
+impl ActivityKind {
+    fn from_type(t: u64) -> Self {
+        match t {
+            ACT_BUILD => ActivityKind::Build,
+            ACT_BUILDS => ActivityKind::Builds,
+            ACT_FILE_TRANSFER => ActivityKind::Download,
+            ACT_SUBSTITUTE => ActivityKind::Substitute,
+            ACT_COPY_PATHS => ActivityKind::CopyPaths,
+            _ => ActivityKind::Other,
+        }
+    }
+}
+
+/// One decoded line of `nix --log-format internal-json` stderr.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    Start {
+        id: u64,
+        kind: ActivityKind,
+        text: String,
+        /// Derivation for builds, store path for substitutions, URL for downloads.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subject: Option<String>,
+    },
+    Stop {
+        id: u64,
+    },
+    Progress {
+        id: u64,
+        done: u64,
+        expected: u64,
+        running: u64,
+        failed: u64,
+    },
+    LogLine {
+        id: u64,
+        line: String,
+    },
+    Phase {
+        id: u64,
+        phase: String,
+    },
+    Message {
+        level: u64,
+        msg: String,
+    },
+    /// A line that wasn't internal-json.
+    Text {
+        line: String,
+    },
+}
+
+impl Event {
+    /// Decode one stderr line. Returns `None` for structured lines we don't
+    /// care about.
+    pub fn parse(line: &str) -> Option<Self> {
+        let Some(json) = line.strip_prefix(PREFIX) else {
+            return Some(Event::Text {
+                line: line.to_string(),
+            });
+        };
+        let v: Value = serde_json::from_str(json).ok()?;
+        let id = v["id"].as_u64().unwrap_or(0);
+        let fields = v["fields"].as_array();
+        let field_str = |i: usize| {
+            fields
+                .and_then(|f| f.get(i))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        let field_u64 = |i: usize| {
+            fields
+                .and_then(|f| f.get(i))
+                .and_then(Value::as_u64)
+                .unwrap_or(0)
+        };
+
+        match v["action"].as_str()? {
+            "start" => {
+                let kind = ActivityKind::from_type(v["type"].as_u64().unwrap_or(0));
+                let subject = match kind {
+                    ActivityKind::Build | ActivityKind::Substitute | ActivityKind::Download => {
+                        field_str(0)
+                    }
+                    _ => None,
+                };
+                Some(Event::Start {
+                    id,
+                    kind,
+                    text: v["text"].as_str().unwrap_or_default().to_string(),
+                    subject,
+                })
+            }
+            "stop" => Some(Event::Stop { id }),
+            "result" => match v["type"].as_u64()? {
+                RES_PROGRESS => Some(Event::Progress {
+                    id,
+                    done: field_u64(0),
+                    expected: field_u64(1),
+                    running: field_u64(2),
+                    failed: field_u64(3),
+                }),
+                RES_SET_EXPECTED => None,
+                RES_BUILD_LOG_LINE => Some(Event::LogLine {
+                    id,
+                    line: field_str(0)?,
+                }),
+                RES_SET_PHASE => Some(Event::Phase {
+                    id,
+                    phase: field_str(0)?,
+                }),
+                _ => None,
+            },
+            "msg" => Some(Event::Message {
+                level: v["level"].as_u64().unwrap_or(LVL_ERROR),
+                msg: v["msg"].as_str().unwrap_or_default().to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Everything we learned from one Nix invocation.
+#[derive(Default)]
+pub struct NixLog {
+    /// Error-level messages, in order.
+    pub errors: Vec<String>,
+    /// Derivations that failed to build.
+    pub failed: Vec<String>,
+    /// Lines that weren't internal-json (older Nix, wrappers, …).
+    pub other: Vec<String>,
+    activities: HashMap<u64, (ActivityKind, String)>,
+    subjects: HashMap<u64, String>,
+    progress: HashMap<ActivityKind, (u64, u64)>,
+}
+
+impl NixLog {
+    pub fn apply(&mut self, ev: &Event) {
+        match ev {
+            Event::Start {
+                id,
+                kind,
+                text,
+                subject,
+            } => {
+                self.activities.insert(*id, (*kind, text.clone()));
+                if let Some(s) = subject {
+                    self.subjects.insert(*id, s.clone());
+                }
+            }
+            Event::Stop { id } => {
+                self.activities.remove(id);
+            }
+            Event::Progress {
+                id, done, expected, ..
+            } => {
+                if let Some((kind, _)) = self.activities.get(id) {
+                    self.progress.insert(*kind, (*done, *expected));
+                }
+            }
+            Event::Message { level, msg } if *level == LVL_ERROR => {
+                self.errors.push(msg.clone());
+                // Nix names the failing derivation in the error; match it
+                // against the builds we saw start.
+                let named: Vec<_> = self
+                    .subjects
+                    .values()
+                    .filter(|drv| drv.ends_with(".drv") && msg.contains(drv.as_str()))
+                    .cloned()
+                    .chain(
+                        nix_hash::parse_mismatches(msg)
+                            .into_iter()
+                            .map(|m| m.path)
+                            .filter(|p| p.ends_with(".drv")),
+                    )
+                    .collect();
+                for drv in named {
+                    self.push_failed(drv);
+                }
+            }
+            Event::Text { line } => self.other.push(line.clone()),
+            _ => {}
+        }
+    }
+
+    fn push_failed(&mut self, drv: String) {
+        if !self.failed.contains(&drv) {
+            self.failed.push(drv);
+        }
+    }
+
+    /// Error messages followed by any unstructured output, for reporting.
+    pub fn text(&self) -> String {
+        self.errors
+            .iter()
+            .chain(&self.other)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Fixed-output hash mismatches Nix reported.
+    pub fn mismatches(&self) -> Vec<HashMismatch> {
+        nix_hash::parse_mismatches(&self.text())
+    }
+
+    fn status_line(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some((done, expected)) = self.progress.get(&ActivityKind::Builds) {
+            parts.push(format!("built {done}/{expected}"));
+        }
+        if let Some((done, expected)) = self.progress.get(&ActivityKind::CopyPaths) {
+            parts.push(format!("fetched {}/{}", mib(*done), mib(*expected)));
+        }
+        // activity ids only grow, so the highest one started most recently
+        let current = self
+            .activities
+            .iter()
+            .filter(|(_, (k, t))| {
+                matches!(k, ActivityKind::Build | ActivityKind::Substitute) && !t.is_empty()
+            })
+            .max_by_key(|(id, _)| **id)
+            .map(|(_, (_, t))| t.as_str());
+        if let Some(t) = current {
+            parts.push(t.to_string());
+        }
+        parts.join(" · ")
+    }
+}
+
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Renders events to stderr as they arrive.
+pub struct Display {
+    mode: Progress,
+    bar_shown: bool,
+}
+
+impl Display {
+    pub fn new(mode: Progress) -> Self {
+        Display {
+            mode: mode.resolve(),
+            bar_shown: false,
+        }
+    }
+
+    pub fn show(&mut self, ev: &Event, log: &NixLog) {
+        let mut err = io::stderr().lock();
+        match self.mode {
+            Progress::None | Progress::Auto => {}
+            Progress::Json => {
+                if let Ok(s) = serde_json::to_string(ev) {
+                    let _ = writeln!(err, "{s}");
+                }
+            }
+            Progress::Plain => match ev {
+                Event::Start { kind, text, .. }
+                    if !text.is_empty()
+                        && matches!(
+                            kind,
+                            ActivityKind::Build | ActivityKind::Substitute | ActivityKind::Download
+                        ) =>
+                {
+                    let _ = writeln!(err, "{text}");
+                }
+                Event::Message { level, msg } if *level <= LVL_WARN => {
+                    let _ = writeln!(err, "{msg}");
+                }
+                Event::Text { line } => {
+                    let _ = writeln!(err, "{line}");
+                }
+                _ => {}
+            },
+            Progress::Bar => {
+                match ev {
+                    Event::Message { level, msg } if *level <= LVL_WARN => {
+                        let _ = writeln!(err, "\r\x1b[K{msg}");
+                    }
+                    Event::Text { line } => {
+                        let _ = writeln!(err, "\r\x1b[K{line}");
+                    }
+                    _ => {}
+                }
+                let status = log.status_line();
+                if !status.is_empty() {
+                    let _ = write!(err, "\r\x1b[K[{status}]");
+                    self.bar_shown = true;
+                }
+            }
+        }
+        let _ = err.flush();
+    }
+
+    pub fn finish(&mut self) {
+        if self.bar_shown {
+            eprint!("\r\x1b[K");
+            self.bar_shown = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = r#"@nix {"action":"start","id":1,"level":0,"parent":0,"text":"","type":104}
+@nix {"action":"start","id":2,"level":3,"parent":0,"text":"building '/nix/store/0sv2pvsyilwvi488kkjw6mbx6h8sv4yv-NVIDIA-Linux-x86_64-570.133.07.run.drv'","type":105,"fields":["/nix/store/0sv2pvsyilwvi488kkjw6mbx6h8sv4yv-NVIDIA-Linux-x86_64-570.133.07.run.drv","",1,1]}
+@nix {"action":"result","id":1,"type":105,"fields":[0,2,1,0]}
+@nix {"action":"result","id":2,"type":101,"fields":["trying https://download.nvidia.com/..."]}
+@nix {"action":"stop","id":2}
+@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m hash mismatch in fixed-output derivation '\u001b[35;1m/nix/store/0sv2pvsyilwvi488kkjw6mbx6h8sv4yv-NVIDIA-Linux-x86_64-570.133.07.run.drv\u001b[0m':\n         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=\n            got:    sha256-LUPmTFgb5e9VTemIixqpADfvbUX1QoTT2dztwI3E3CY="}
+@nix {"action":"result","id":1,"type":105,"fields":[0,2,0,1]}
+@nix {"action":"msg","level":0,"msg":"error: 1 dependencies of derivation '/nix/store/d15fr4ik98n677hri556aqwkds0cz6sb-nvidia-x11-570.133.07.drv' failed to build"}
+@nix {"action":"stop","id":1}
+warning: unstructured line"#;
+
+    #[test]
+    fn decodes_activity_stream() {
+        let events: Vec<_> = LOG.lines().filter_map(Event::parse).collect();
+        assert_eq!(events.len(), 10);
+        assert_eq!(
+            events[2],
+            Event::Progress {
+                id: 1,
+                done: 0,
+                expected: 2,
+                running: 1,
+                failed: 0
+            }
+        );
+
+        let mut log = NixLog::default();
+        for ev in &events {
+            log.apply(ev);
+        }
+        assert_eq!(log.errors.len(), 2);
+        assert_eq!(
+            log.failed,
+            ["/nix/store/0sv2pvsyilwvi488kkjw6mbx6h8sv4yv-NVIDIA-Linux-x86_64-570.133.07.run.drv"]
+        );
+        assert_eq!(log.other, ["warning: unstructured line"]);
+        assert_eq!(
+            log.mismatches()[0].got,
+            "sha256-LUPmTFgb5e9VTemIixqpADfvbUX1QoTT2dztwI3E3CY="
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use crate::config::NixOptions;
+use regex::Regex;
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+/// Where multi-user installs put the default profile; systemd units often
+/// don't have it on their PATH.
+const DEFAULT_PROFILE_BIN: &str = "/nix/var/nix/profiles/default/bin";
+
+/// Which Nix implementation `nix --version` reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Implementation {
+    Nix,
+    Lix,
+    Determinate,
+    Unknown,
+}
+
+impl fmt::Display for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Implementation::Nix => "Nix",
+            Implementation::Lix => "Lix",
+            Implementation::Determinate => "Determinate Nix",
+            Implementation::Unknown => "unknown Nix",
+        })
+    }
+}
+
+/// How we drive builds on this installation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frontend {
+    /// `nix build`, `nix-command` already enabled.
+    NixCommand,
+    /// `nix --extra-experimental-features nix-command build`.
+    NixCommandFlag,
+    /// `nix-build` / `nix-store`.
+    Legacy,
+}
+
+impl fmt::Display for Frontend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Frontend::NixCommand => "nix build",
+            Frontend::NixCommandFlag => "nix build (--extra-experimental-features nix-command)",
+            Frontend::Legacy => "nix-build",
+        })
+    }
+}
+
+/// What we found out about the local Nix installation.
+#[derive(Debug)]
+pub struct NixInstall {
+    pub nix: Option<PathBuf>,
+    pub nix_build: Option<PathBuf>,
+    pub nix_store: Option<PathBuf>,
+    pub nix_instantiate: Option<PathBuf>,
+    /// First line of `nix --version` (or `nix-build --version`).
+    pub version_line: Option<String>,
+    pub implementation: Implementation,
+    /// Version of the Nix language/CLI, e.g. `2.24.9`.
+    pub version: Option<(u32, u32, u32)>,
+    pub experimental_features: Vec<String>,
+}
+
+impl NixInstall {
+    /// Probe once per process and cache the result.
+    pub fn get() -> &'static NixInstall {
+        static PROBED: OnceLock<NixInstall> = OnceLock::new();
+        PROBED.get_or_init(probe)
+    }
+
+    pub fn frontend(&self) -> Option<Frontend> {
+        match (&self.nix, &self.nix_build) {
+            // `nix build` only grew the experimental gate (and --log-format) in 2.4
+            (Some(_), Some(_)) if self.version.is_some_and(|v| v < (2, 4, 0)) => {
+                Some(Frontend::Legacy)
+            }
+            (Some(_), None) if self.version.is_some_and(|v| v < (2, 4, 0)) => {
+                Some(Frontend::NixCommand)
+            }
+            (Some(_), _) if self.has_feature("nix-command") => Some(Frontend::NixCommand),
+            (Some(_), _) => Some(Frontend::NixCommandFlag),
+            (None, Some(_)) => Some(Frontend::Legacy),
+            (None, None) => None,
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.experimental_features.iter().any(|f| f == feature)
+    }
+
+    /// Whether this Nix understands `--log-format internal-json`.
+    pub fn supports_internal_json(&self) -> bool {
+        self.version.is_none_or(|v| v >= (2, 4, 0))
+    }
+
+    /// A `nix <subcommand>` invocation with `nix-command` enabled if needed.
+    /// `None` when only the legacy tools are available.
+    pub fn nix_command(&self) -> Option<Command> {
+        let nix = self.nix.as_ref()?;
+        let mut cmd = Command::new(nix);
+        match self.frontend()? {
+            Frontend::NixCommand => {}
+            Frontend::NixCommandFlag => {
+                cmd.args(["--extra-experimental-features", "nix-command"]);
+            }
+            Frontend::Legacy => return None,
+        }
+        Some(cmd)
+    }
+
+    /// `nix-store`, found or not (then the error surfaces on spawn).
+    pub fn nix_store_command(&self) -> Command {
+        Command::new(self.nix_store.as_deref().unwrap_or(Path::new("nix-store")))
+    }
+
+    /// Identify the `<nixpkgs>` our templates import: its `.git-revision`
+    /// (channels ship one) or else its resolved store path.
+    pub fn nixpkgs_revision(&self, opts: &NixOptions) -> Option<String> {
+        let mut cmd = Command::new(self.nix_instantiate.as_ref()?);
+        cmd.args(["--find-file", "nixpkgs"]);
+        opts.apply(&mut cmd);
+        let path = first_line(&mut cmd)?;
+        let path = Path::new(&path).canonicalize().ok()?;
+        match std::fs::read_to_string(path.join(".git-revision")) {
+            Ok(rev) if !rev.trim().is_empty() => Some(rev.trim().to_string()),
+            _ => Some(path.display().to_string()),
+        }
+    }
+
+    /// Human-readable one-line summary for `status`.
+    pub fn describe(&self) -> String {
+        let Some(frontend) = self.frontend() else {
+            return format!("not found (looked in PATH and {DEFAULT_PROFILE_BIN})");
+        };
+        let version = self
+            .version
+            .map(|(a, b, c)| format!(" {a}.{b}.{c}"))
+            .unwrap_or_default();
+        let features = if self.experimental_features.is_empty() {
+            "none".to_string()
+        } else {
+            self.experimental_features.join(" ")
+        };
+        format!(
+            "{}{} via {} (experimental features: {})",
+            self.implementation, version, frontend, features
+        )
+    }
+}
+
+fn probe() -> NixInstall {
+    let nix = find_exe("nix");
+    let nix_build = find_exe("nix-build");
+    let nix_store = find_exe("nix-store");
+    let nix_instantiate = find_exe("nix-instantiate");
+
+    let version_line = nix
+        .as_ref()
+        .or(nix_build.as_ref())
+        .and_then(|exe| first_line(Command::new(exe).arg("--version")));
+    let (implementation, version) = version_line
+        .as_deref()
+        .map(parse_version)
+        .unwrap_or((Implementation::Unknown, None));
+
+    // no `--extra-experimental-features` here: it would enable what we probe for
+    let experimental_features = nix
+        .as_ref()
+        .and_then(|nix| {
+            // `config show` is 2.20+, `show-config` everything before
+            [
+                &["config", "show", "experimental-features"][..],
+                &["show-config"],
+            ]
+            .into_iter()
+            .find_map(|args| {
+                let out = Command::new(nix).args(args).output().ok()?;
+                parse_features(
+                    out.status.success(),
+                    &String::from_utf8_lossy(&out.stdout),
+                    &String::from_utf8_lossy(&out.stderr),
+                )
+            })
+        })
+        .unwrap_or_default();
+
+    NixInstall {
+        nix,
+        nix_build,
+        nix_store,
+        nix_instantiate,
+        version_line,
+        implementation,
+        version,
+        experimental_features,
+    }
+}
+
+/// Enabled features from `nix config show experimental-features` (the bare
+/// value) or `nix show-config` (every setting). Both are `nix-command`
+/// subcommands, so Nix refusing them means none of that is enabled. `None`
+/// if the output tells nothing, e.g. `config` is unknown to this Nix.
+fn parse_features(success: bool, stdout: &str, stderr: &str) -> Option<Vec<String>> {
+    if !success {
+        return stderr
+            .contains("experimental Nix feature 'nix-command' is disabled")
+            .then(Vec::new);
+    }
+    let value = stdout
+        .lines()
+        .find_map(|l| l.strip_prefix("experimental-features = "))
+        .or_else(|| stdout.lines().next().filter(|l| !l.contains(" = ")))?;
+    Some(value.split_whitespace().map(str::to_string).collect())
+}
+
+/// `nix (Nix) 2.24.9`, `nix (Lix, like Nix) 2.91.1`,
+/// `nix (Determinate Nix 3.6.2) 2.29.0`, `nix-build (Nix) 2.3.16`
+fn parse_version(line: &str) -> (Implementation, Option<(u32, u32, u32)>) {
+    let implementation = if line.contains("Lix") {
+        Implementation::Lix
+    } else if line.contains("Determinate") {
+        Implementation::Determinate
+    } else if line.contains("(Nix)") {
+        Implementation::Nix
+    } else {
+        Implementation::Unknown
+    };
+    let re = Regex::new(r"(\d+)\.(\d+)(?:\.(\d+))?\S*\s*$").unwrap();
+    let version = re.captures(line.trim()).map(|c| {
+        let n = |i: usize| c.get(i).map_or(0, |m| m.as_str().parse().unwrap_or(0));
+        (n(1), n(2), n(3))
+    });
+    (implementation, version)
+}
+
+fn find_exe(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .chain([PathBuf::from(DEFAULT_PROFILE_BIN)])
+        .map(|dir| dir.join(name))
+        .find(|p| is_executable(p))
+}
+
+fn is_executable(p: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    p.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+fn first_line(cmd: &mut Command) -> Option<String> {
+    let out = cmd.output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .next()
+        .map(|l| l.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn install(
+        nix: bool,
+        nix_build: bool,
+        version: (u32, u32, u32),
+        features: &[&str],
+    ) -> NixInstall {
+        NixInstall {
+            nix: nix.then(|| PathBuf::from("/bin/nix")),
+            nix_build: nix_build.then(|| PathBuf::from("/bin/nix-build")),
+            nix_store: None,
+            nix_instantiate: None,
+            version_line: None,
+            implementation: Implementation::Nix,
+            version: Some(version),
+            experimental_features: features.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn features_are_probed_without_enabling_them() {
+        let disabled = "error: experimental Nix feature 'nix-command' is disabled; \
+                        add '--extra-experimental-features nix-command' to enable it\n";
+        assert_eq!(parse_features(false, "", disabled), Some(vec![]));
+        assert_eq!(
+            parse_features(true, "nix-command flakes\n", ""),
+            Some(vec!["nix-command".to_string(), "flakes".to_string()])
+        );
+        assert_eq!(parse_features(true, "\n", ""), Some(vec![]));
+        let show_config = "cores = 0\nexperimental-features = nix-command\nmax-jobs = 8\n";
+        assert_eq!(
+            parse_features(true, show_config, ""),
+            Some(vec!["nix-command".to_string()])
+        );
+        // 2.19 has no `config`: try `show-config` next
+        assert_eq!(
+            parse_features(false, "", "error: 'config' is not a recognised command\n"),
+            None
+        );
+
+        let off = install(true, true, (2, 24, 9), &[]);
+        assert_eq!(off.frontend(), Some(Frontend::NixCommandFlag));
+        let on = install(true, true, (2, 24, 9), &["nix-command"]);
+        assert_eq!(on.frontend(), Some(Frontend::NixCommand));
+    }
+
+    #[test]
+    fn versions_of_every_implementation() {
+        assert_eq!(
+            parse_version("nix (Nix) 2.24.9"),
+            (Implementation::Nix, Some((2, 24, 9)))
+        );
+        assert_eq!(
+            parse_version("nix (Lix, like Nix) 2.91.1"),
+            (Implementation::Lix, Some((2, 91, 1)))
+        );
+        assert_eq!(
+            parse_version("nix (Determinate Nix 3.6.2) 2.29.0"),
+            (Implementation::Determinate, Some((2, 29, 0)))
+        );
+        assert_eq!(
+            parse_version("nix-build (Nix) 2.3.16"),
+            (Implementation::Nix, Some((2, 3, 16)))
+        );
+    }
+
+    #[test]
+    fn frontend_selection() {
+        let f = |i: NixInstall| i.frontend();
+        assert_eq!(
+            f(install(true, true, (2, 24, 9), &["nix-command", "flakes"])),
+            Some(Frontend::NixCommand)
+        );
+        assert_eq!(
+            f(install(true, true, (2, 24, 9), &[])),
+            Some(Frontend::NixCommandFlag)
+        );
+        assert_eq!(
+            f(install(false, true, (2, 24, 9), &[])),
+            Some(Frontend::Legacy)
+        );
+        assert_eq!(
+            f(install(true, true, (2, 3, 16), &[])),
+            Some(Frontend::Legacy)
+        );
+        assert_eq!(f(install(false, false, (2, 24, 9), &[])), None);
+    }
+}
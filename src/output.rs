@@ -0,0 +1,139 @@
+//! `--output json`: every command prints exactly one JSON document on
+//! stdout, errors included. Messages meant for humans go to stderr instead.
+//! The documents are described in the README; fields are only ever added.
+
+use crate::detect::Driver;
+use crate::error::{self, Kind};
+use crate::plan::Plan;
+use crate::state::State;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json(on: bool) {
+    JSON.store(on, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// `println!` for human-readable progress: stdout normally, stderr when
+/// stdout carries JSON.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_json() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+pub(crate) use say;
+
+/// Print `value` as JSON, or run `text` to print it for humans.
+pub fn report<T: Serialize + ?Sized>(value: &T, text: impl FnOnce()) {
+    if is_json() {
+        match serde_json::to_string_pretty(value) {
+            Ok(json) => println!("{json}"),
+            Err(e) => eprintln!("Error: serializing output: {e}"),
+        }
+    } else {
+        text()
+    }
+}
+
+/// `status`
+#[derive(Serialize)]
+pub struct Status<'a> {
+    pub detected: &'a Driver,
+    /// One-line description of the Nix installation.
+    pub nix: String,
+    /// The full `state.json`, `null` before the first sync.
+    pub state: Option<&'a State>,
+    /// What changed in the hardware since the last sync.
+    pub hardware_changes: Vec<String>,
+}
+
+/// `build`
+#[derive(Serialize)]
+pub struct Built<'a> {
+    pub driver: &'a Driver,
+    pub path: String,
+    /// Binary cache the farm was copied to.
+    pub published: Option<String>,
+}
+
+/// `sync`
+#[derive(Serialize)]
+pub struct Synced<'a> {
+    pub driver: &'a Driver,
+    pub path: String,
+    /// `false` when `--if-changed` found nothing to do.
+    pub changed: bool,
+    pub generation: Option<u32>,
+    pub published: Option<String>,
+}
+
+/// `build --dry-run` and `sync --dry-run`
+#[derive(Serialize)]
+pub struct Planned<'a> {
+    #[serde(flatten)]
+    pub plan: &'a Plan,
+    /// The farm `sync` would switch away from.
+    pub active: Option<String>,
+}
+
+/// Commands that only do something, such as `install`.
+#[derive(Serialize)]
+pub struct Done {
+    pub message: String,
+}
+
+impl Done {
+    pub fn report(message: impl Into<String>) {
+        let done = Done {
+            message: message.into(),
+        };
+        report(&done, || println!("{}", done.message));
+    }
+
+    /// Only the JSON document, for commands that already said so in text.
+    pub fn json_only(message: impl Into<String>) {
+        let done = Done {
+            message: message.into(),
+        };
+        report(&done, || {});
+    }
+}
+
+/// Printed instead of the command's document when it fails.
+#[derive(Serialize)]
+pub struct Failed {
+    pub error: ErrorInfo,
+}
+
+#[derive(Serialize)]
+pub struct ErrorInfo {
+    /// The outermost error message.
+    pub message: String,
+    /// The underlying causes, outermost first.
+    pub causes: Vec<String>,
+    /// What failed, e.g. `build_failed`; see the README for the list.
+    pub kind: Kind,
+    pub exit_code: i32,
+}
+
+impl Failed {
+    pub fn new(e: &anyhow::Error) -> Self {
+        Failed {
+            error: ErrorInfo {
+                message: e.to_string(),
+                causes: e.chain().skip(1).map(ToString::to_string).collect(),
+                kind: Kind::of(e),
+                exit_code: error::exit_code(e),
+            },
+        }
+    }
+}
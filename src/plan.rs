@@ -0,0 +1,205 @@
+use crate::hash_store::Layer;
+use crate::state::{self, GCROOT_SYMLINK};
+use regex::Regex;
+use serde::Serialize;
+use std::fs;
+
+/// Where the NVIDIA hash for a dry run came from.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum PlannedHash {
+    Known {
+        hash: String,
+        layer: Layer,
+    },
+    /// Not cached yet; a real run downloads the driver to learn it.
+    Unresolved,
+}
+
+/// What a build or sync would do, without doing it.
+#[derive(Default, Serialize)]
+pub struct Plan {
+    pub driver: String,
+    pub nvidia_hash: Option<PlannedHash>,
+    pub to_build: Vec<String>,
+    pub to_fetch: Vec<String>,
+    pub download: Option<String>,
+    pub unpacked: Option<String>,
+    /// The farm's store path, when Nix reports it.
+    pub out_path: Option<String>,
+}
+
+impl Plan {
+    /// Fill in what `nix build --dry-run` printed on stderr, e.g.
+    ///
+    /// ```text
+    /// these 2 derivations will be built:
+    ///   /nix/store/…-nix-opengl-driver.drv
+    /// these 12 paths will be fetched (45.30 MiB download, 230.10 MiB unpacked):
+    ///   /nix/store/…-mesa-25.0.5
+    /// ```
+    pub fn parse_dry_run(&mut self, stderr: &str) {
+        let ansi = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+        let text = ansi.replace_all(stderr, "");
+        let sizes = Regex::new(r"\(([\d.]+ \w+) download(?:, ([\d.]+ \w+) unpacked)?\)").unwrap();
+
+        #[derive(PartialEq)]
+        enum Section {
+            None,
+            Build,
+            Fetch,
+        }
+        let mut section = Section::None;
+
+        for line in text.lines() {
+            let trimmed = line.trim();
+            if line.starts_with(char::is_whitespace) && trimmed.starts_with("/nix/store/") {
+                match section {
+                    Section::Build => self.to_build.push(trimmed.to_string()),
+                    Section::Fetch => self.to_fetch.push(trimmed.to_string()),
+                    Section::None => {}
+                }
+            } else if trimmed.ends_with("will be built:") {
+                section = Section::Build;
+            } else if trimmed.contains("will be fetched") {
+                section = Section::Fetch;
+                if let Some(c) = sizes.captures(trimmed) {
+                    self.download = Some(c[1].to_string());
+                    self.unpacked = c.get(2).map(|m| m.as_str().to_string());
+                }
+            } else {
+                section = Section::None;
+            }
+        }
+    }
+
+    /// Paths that can only be obtained over the network: substitutes and
+    /// fixed-output derivations (`fetchurl` and friends) still to build.
+    pub fn downloads(&self) -> Vec<String> {
+        let fixed_output =
+            |drv: &String| fs::read_to_string(drv).is_ok_and(|text| is_fixed_output(&text));
+        let mut out: Vec<_> = self
+            .to_build
+            .iter()
+            .filter(|d| fixed_output(d))
+            .cloned()
+            .collect();
+        out.extend(self.to_fetch.iter().cloned());
+        out
+    }
+
+    /// Print the plan; with `switch`, also what `sync` would change.
+    pub fn print(&self, switch: bool) {
+        println!("Driver:        {}", self.driver);
+        match &self.nvidia_hash {
+            Some(PlannedHash::Known { hash, layer }) => {
+                println!("NVIDIA hash:   {} ({} store)", hash, layer)
+            }
+            Some(PlannedHash::Unresolved) => println!(
+                "NVIDIA hash:   unknown; a real run first downloads the driver to resolve it"
+            ),
+            None => {}
+        }
+
+        if self.to_build.is_empty() && self.to_fetch.is_empty() {
+            println!("Nothing to build or fetch");
+        }
+        if !self.to_build.is_empty() {
+            println!("Will build ({}):", self.to_build.len());
+            for p in &self.to_build {
+                println!("  {p}");
+            }
+        }
+        if !self.to_fetch.is_empty() {
+            let size = match (&self.download, &self.unpacked) {
+                (Some(d), Some(u)) => format!(", {d} download, {u} unpacked"),
+                (Some(d), None) => format!(", {d} download"),
+                _ => String::new(),
+            };
+            println!("Will fetch ({}{}):", self.to_fetch.len(), size);
+            for p in &self.to_fetch {
+                println!("  {p}");
+            }
+        }
+        if let Some(out) = &self.out_path {
+            println!("Farm:          {out}");
+        }
+
+        if !switch {
+            return;
+        }
+        let new = self.out_path.as_deref().unwrap_or("<new farm>");
+        match fs::read_link(GCROOT_SYMLINK) {
+            Ok(old) if old.to_str() == Some(new) => {
+                println!("GC root:       {GCROOT_SYMLINK} already points at {new}")
+            }
+            Ok(old) => println!(
+                "GC root:       {GCROOT_SYMLINK}: {} -> {new}",
+                old.display()
+            ),
+            Err(_) => println!("GC root:       {GCROOT_SYMLINK}: <none> -> {new}"),
+        }
+        match state::State::load() {
+            Some(s) => println!("State:         detected {} -> {}", s.detected, self.driver),
+            None => println!("State:         <none> -> detected {}", self.driver),
+        }
+    }
+}
+
+/// A fixed-output derivation names its output's hash up front:
+/// `Derive([("out","/nix/store/…","sha256","<hash>")],…`.
+fn is_fixed_output(drv: &str) -> bool {
+    Regex::new(r#"^Derive\(\[\("[^"]*","[^"]*","[^"]+","[^"]+"\)"#)
+        .unwrap()
+        .is_match(drv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dry_run_output() {
+        const OUTPUT: &str = "\
+these 2 derivations will be built:
+  /nix/store/0sv2pvsyilwvi488kkjw6mbx6h8sv4yv-NVIDIA-Linux-x86_64-570.133.07.run.drv
+  /nix/store/r0ahk6mdw9mr5gky8g4dn9z4v0f6n4sj-nix-opengl-driver.drv
+these 3 paths will be fetched (45.30 MiB download, 230.10 MiB unpacked):
+  /nix/store/2rqsbm2x3ilmcjgxp8ayc1hhn3ypxbzi-mesa-25.0.5
+  /nix/store/7m3k3bhqk7r6fm8kpn4s0ydk9nx0wlrb-libva-2.22.0
+  /nix/store/p8mfa5k7dppkb0v6dz2wiclxa8yjmyfl-vulkan-loader-1.4.309.0
+";
+        let mut plan = Plan::default();
+        plan.parse_dry_run(OUTPUT);
+        assert_eq!(plan.to_build.len(), 2);
+        assert_eq!(plan.to_fetch.len(), 3);
+        assert_eq!(plan.download.as_deref(), Some("45.30 MiB"));
+        assert_eq!(plan.unpacked.as_deref(), Some("230.10 MiB"));
+    }
+
+    #[test]
+    fn parses_legacy_singular_output() {
+        const OUTPUT: &str = "\
+this derivation will be built:
+  /nix/store/r0ahk6mdw9mr5gky8g4dn9z4v0f6n4sj-nix-opengl-driver.drv
+this path will be fetched (1.02 MiB download):
+  /nix/store/7m3k3bhqk7r6fm8kpn4s0ydk9nx0wlrb-libva-2.22.0
+";
+        let mut plan = Plan::default();
+        plan.parse_dry_run(OUTPUT);
+        assert_eq!(plan.to_build.len(), 1);
+        assert_eq!(plan.to_fetch.len(), 1);
+        assert_eq!(plan.download.as_deref(), Some("1.02 MiB"));
+        assert_eq!(plan.unpacked, None);
+    }
+
+    #[test]
+    fn recognises_fixed_output_derivations() {
+        assert!(is_fixed_output(
+            r#"Derive([("out","/nix/store/9kmnn2bbsnw3wz5h8mkkx4z1vc6axfyi-NVIDIA-Linux-x86_64-570.133.07.run","sha256","2d43e64c581be5ef554de9888b1aa90037ef6d45f54284d3d9dced808dc4dc26")],[],[],"builtin","builtin:fetchurl",[],[])"#
+        ));
+        assert!(!is_fixed_output(
+            r#"Derive([("out","/nix/store/r0ahk6mdw9mr5gky8g4dn9z4v0f6n4sj-nix-opengl-driver","","")],[],[],"x86_64-linux","/bin/sh",[],[])"#
+        ));
+    }
+}
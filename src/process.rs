@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use std::{
+    io::{BufRead, BufReader},
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a timed-out child gets between SIGTERM and SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+const POLL: Duration = Duration::from_millis(200);
+
+/// Process group of the child currently running, 0 if none.
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
+/// Last SIGINT/SIGTERM we received, 0 if none.
+static SIGNALLED: AtomicI32 = AtomicI32::new(0);
+
+/// Why a child was stopped before it finished on its own.
+#[derive(Debug, thiserror::Error)]
+pub enum Aborted {
+    #[error("`{0}` timed out after {1:?}")]
+    Timeout(String, Duration),
+    #[error("`{0}` interrupted by signal {1}")]
+    Interrupted(String, i32),
+}
+
+impl Aborted {
+    /// Exit status for the whole tool: 124 like timeout(1), 128+N for signals.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Aborted::Timeout(..) => 124,
+            Aborted::Interrupted(_, sig) => 128 + sig,
+        }
+    }
+}
+
+extern "C" fn forward(sig: libc::c_int) {
+    SIGNALLED.store(sig, Ordering::SeqCst);
+    let pgid = CHILD_PGID.load(Ordering::SeqCst);
+    if pgid > 0 {
+        unsafe { libc::kill(-pgid, sig) };
+    }
+}
+
+fn set_handlers(handler: libc::sighandler_t) {
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Forwards SIGINT/SIGTERM to the child while it runs. Once it's done they
+/// terminate us as usual again, so a signal between two children can't be
+/// swallowed while the steps after the build carry on.
+struct Forwarding;
+
+impl Forwarding {
+    fn install() -> Self {
+        set_handlers(forward as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        Forwarding
+    }
+}
+
+impl Drop for Forwarding {
+    fn drop(&mut self) {
+        set_handlers(libc::SIG_DFL);
+    }
+}
+
+/// Kills the child's process group if we bail out early.
+struct Running {
+    child: Child,
+    pgid: i32,
+    done: bool,
+}
+
+impl Running {
+    fn signal(&self, sig: libc::c_int) {
+        unsafe { libc::kill(-self.pgid, sig) };
+    }
+
+    /// SIGTERM, give it a moment, then SIGKILL.
+    fn terminate(&mut self) -> Result<ExitStatus> {
+        self.signal(libc::SIGTERM);
+        let deadline = Instant::now() + KILL_GRACE;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(POLL);
+        }
+        self.signal(libc::SIGKILL);
+        Ok(self.child.wait()?)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        CHILD_PGID.store(0, Ordering::SeqCst);
+        if !self.done {
+            self.signal(libc::SIGKILL);
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Run `cmd` in its own process group, feeding each stderr line to
+/// `on_line`. SIGINT/SIGTERM are forwarded to the group, and after
+/// `timeout` the group is terminated; both surface as [`Aborted`].
+pub fn run_streaming(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    mut on_line: impl FnMut(&str),
+) -> Result<ExitStatus> {
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    let _forwarding = Forwarding::install();
+    // interrupted between two children: don't start another one
+    if let sig @ 1.. = SIGNALLED.load(Ordering::SeqCst) {
+        return Err(Aborted::Interrupted(name, sig).into());
+    }
+
+    let mut child = cmd
+        .process_group(0)
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("spawning `{name}`"))?;
+    let pgid = child.id() as i32;
+    let stderr = child.stderr.take().expect("stderr is piped");
+    CHILD_PGID.store(pgid, Ordering::SeqCst);
+    let mut running = Running {
+        child,
+        pgid,
+        done: false,
+    };
+    // a signal between installing the handler and the store above had no
+    // group to go to
+    if let sig @ 1.. = SIGNALLED.load(Ordering::SeqCst) {
+        running.terminate()?;
+        running.done = true;
+        return Err(Aborted::Interrupted(name, sig).into());
+    }
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut eof = false;
+    let status = loop {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            running.terminate()?;
+            running.done = true;
+            return Err(Aborted::Timeout(name, timeout.unwrap()).into());
+        }
+        // not waiting for EOF: a grandchild may hold stderr open for good
+        if let Some(status) = running.child.try_wait()? {
+            break status;
+        }
+        if eof {
+            thread::sleep(POLL);
+            continue;
+        }
+        match rx.recv_timeout(POLL) {
+            Ok(line) => on_line(&line.context("reading child stderr")?),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => eof = true,
+        }
+    };
+    running.done = true;
+    // pick up what it wrote just before exiting, until the pipe goes quiet
+    while let Ok(line) = rx.recv_timeout(POLL) {
+        on_line(&line.context("reading child stderr")?);
+    }
+
+    match SIGNALLED.load(Ordering::SeqCst) {
+        0 => Ok(status),
+        sig => Err(Aborted::Interrupted(name, sig).into()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Signal dispositions are per process; tests that run children hold
+    /// this so one can't look while another is forwarding.
+    pub(crate) static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn streams_stderr_lines() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let mut lines = Vec::new();
+        let status = run_streaming(
+            Command::new("sh").args(["-c", "echo one >&2; echo two >&2"]),
+            None,
+            |l| lines.push(l.to_string()),
+        )
+        .unwrap();
+        assert!(status.success());
+        assert_eq!(lines, ["one", "two"]);
+    }
+
+    #[test]
+    fn a_grandchild_holding_stderr_does_not_block() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let start = Instant::now();
+        let mut lines = Vec::new();
+        let status = run_streaming(
+            Command::new("sh").args(["-c", "sleep 5 & echo done >&2"]),
+            None,
+            |l| lines.push(l.to_string()),
+        )
+        .unwrap();
+        assert!(status.success());
+        assert_eq!(lines, ["done"]);
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn timeout_kills_the_process_group() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let start = Instant::now();
+        let err = run_streaming(
+            Command::new("sh").args(["-c", "sleep 30 & sleep 30"]),
+            Some(Duration::from_millis(300)),
+            |_| {},
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Aborted>(),
+            Some(Aborted::Timeout(..))
+        ));
+        assert!(start.elapsed() < KILL_GRACE);
+    }
+
+    #[test]
+    fn signals_are_only_forwarded_while_a_child_runs() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let disposition = || unsafe {
+            let mut old: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGTERM, std::ptr::null(), &mut old);
+            old.sa_sigaction
+        };
+        run_streaming(&mut Command::new("true"), None, |_| {}).unwrap();
+        assert_eq!(disposition(), libc::SIG_DFL);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::build::render_nix_expr;
+use crate::detect::Driver;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// What a machine needs from its farm, so another host can build it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Profile {
+    /// `nvidia` or `mesa`.
+    pub driver: String,
+    /// NVIDIA driver version; the userspace libraries must match the module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Nix system, e.g. `x86_64-linux`.
+    pub arch: String,
+    /// Packages the farm provides.
+    pub components: Vec<String>,
+}
+
+/// The Nix system string of this host.
+fn local_arch() -> String {
+    format!("{}-linux", std::env::consts::ARCH)
+}
+
+/// The packages the expression for `driver` puts into the farm: the
+/// `paths` list of `buildEnv`, without their arguments.
+fn components(driver: &Driver) -> Vec<String> {
+    let expr = render_nix_expr(driver, None).expect("templates render");
+    let Some((_, list)) = expr.split_once("paths = with pkgs; [") else {
+        return vec![];
+    };
+    let list = list.split("\n  ];").next().unwrap_or_default();
+    let package = Regex::new(r"^\s*\(*([A-Za-z][\w.-]*)").unwrap();
+    list.lines()
+        .map(|l| l.split('#').next().unwrap_or_default())
+        // settings passed to a package, not packages
+        .filter(|l| !l.contains('='))
+        .filter_map(|l| package.captures(l).map(|c| c[1].to_string()))
+        .collect()
+}
+
+impl Profile {
+    /// Profile this machine, running `driver`.
+    pub fn detect(driver: &Driver) -> Profile {
+        Profile {
+            driver: match driver {
+                Driver::Nvidia(_) => "nvidia".into(),
+                Driver::Mesa => "mesa".into(),
+            },
+            version: match driver {
+                Driver::Nvidia(v) => Some(v.clone()),
+                Driver::Mesa => None,
+            },
+            arch: local_arch(),
+            components: components(driver),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Profile> {
+        let txt =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&txt).with_context(|| format!("parsing profile {}", path.display()))
+    }
+
+    /// Write to `file`, or stdout for `None` or `-`.
+    pub fn write(&self, file: Option<&Path>) -> Result<()> {
+        let txt = serde_json::to_string_pretty(self)?;
+        match file.filter(|f| *f != Path::new("-")) {
+            Some(f) => {
+                fs::write(f, txt + "\n").with_context(|| format!("writing {}", f.display()))?
+            }
+            None => println!("{txt}"),
+        }
+        Ok(())
+    }
+
+    /// The driver to build a farm for, checking this host can build it.
+    pub fn driver(&self) -> Result<Driver> {
+        if self.arch != local_arch() {
+            bail!(
+                "profile is for {}, but this host builds {} farms",
+                self.arch,
+                local_arch()
+            );
+        }
+        let driver = match (self.driver.as_str(), &self.version) {
+            ("nvidia", Some(v)) => Driver::Nvidia(v.clone()),
+            ("nvidia", None) => bail!("NVIDIA profile without a driver version"),
+            ("mesa", _) => Driver::Mesa,
+            (other, _) => bail!("unknown driver `{other}` in profile"),
+        };
+        let ours = components(&driver);
+        let missing: Vec<_> = self
+            .components
+            .iter()
+            .filter(|c| !ours.contains(c))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "profile asks for components this version doesn't build: {}",
+                missing.join(", ")
+            );
+        }
+        Ok(driver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_come_from_the_expression() {
+        let mesa = components(&Driver::Mesa);
+        let nvidia = components(&Driver::Nvidia("570.133.07".into()));
+        assert_eq!(mesa.first().map(String::as_str), Some("libglvnd"));
+        assert!(mesa.ends_with(&["mesa".into(), "mesa.opencl".into()]));
+        assert!(nvidia.ends_with(&[
+            "nvidiaPackages.mkDriver".into(),
+            "nvidia-vaapi-driver".into()
+        ]));
+        assert_eq!(mesa.len(), nvidia.len());
+        assert!(!nvidia.iter().any(|c| c.starts_with("mesa")));
+        assert!(!nvidia.iter().any(|c| c == "version" || c == "libsOnly"));
+    }
+
+    #[test]
+    fn round_trips_to_a_driver() {
+        let p = Profile {
+            driver: "nvidia".into(),
+            version: Some("570.133.07".into()),
+            arch: local_arch(),
+            components: components(&Driver::Nvidia("570.133.07".into())),
+        };
+        let json = serde_json::to_string(&p).unwrap();
+        let back: Profile = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, p);
+        assert!(matches!(back.driver(), Ok(Driver::Nvidia(v)) if v == "570.133.07"));
+
+        let foreign = Profile {
+            arch: "m68k-linux".into(),
+            ..back
+        };
+        assert!(foreign.driver().is_err());
+    }
+}
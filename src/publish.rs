@@ -0,0 +1,120 @@
+use crate::build::BuildOptions;
+use crate::config::Publish;
+use crate::error::Error;
+use crate::nix_probe::NixInstall;
+use crate::output::say;
+use crate::process;
+use anyhow::{bail, Result};
+use log::info;
+use std::{path::Path, process::Command};
+
+/// `nix`, whether or not its new CLI needs enabling. Before 2.4 `copy` and
+/// `sign-paths` worked without the experimental gate.
+fn nix(install: &NixInstall) -> Result<Command> {
+    match (install.nix_command(), &install.nix) {
+        (Some(cmd), _) => Ok(cmd),
+        (None, Some(nix)) => Ok(Command::new(nix)),
+        (None, None) => Err(Error::NixMissing(install.describe()).into()),
+    }
+}
+
+/// `nix store sign` was `nix sign-paths` before 2.4.
+fn sign_args(version: Option<(u32, u32, u32)>) -> &'static [&'static str] {
+    if version.is_some_and(|v| v < (2, 4, 0)) {
+        &["sign-paths"]
+    } else {
+        &["store", "sign"]
+    }
+}
+
+/// Run `cmd`, turning a failure into an error with Nix's output.
+fn run(mut cmd: Command, what: &str, opts: &BuildOptions) -> Result<()> {
+    opts.nix.apply(&mut cmd);
+    let mut stderr = String::new();
+    let status = process::run_streaming(&mut cmd, opts.timeout, |line| {
+        stderr.push_str(line);
+        stderr.push('\n');
+    })?;
+    if !status.success() {
+        bail!("`{what}` failed:\n{stderr}");
+    }
+    Ok(())
+}
+
+/// Sign `farm`'s closure with the configured key, if any, and copy it to the
+/// binary cache so machines with the same driver can substitute it.
+pub fn publish(farm: &Path, to: &Publish, opts: &BuildOptions) -> Result<()> {
+    let install = NixInstall::get();
+    if let Some(key) = &to.secret_key {
+        info!("Signing {} with {}", farm.display(), key.display());
+        let mut cmd = nix(install)?;
+        cmd.args(sign_args(install.version))
+            .arg("--recursive")
+            .arg("--key-file")
+            .arg(key)
+            .arg(farm);
+        run(cmd, "nix store sign", opts)?;
+    }
+
+    info!("Copying {} to {}", farm.display(), to.to);
+    let mut cmd = nix(install)?;
+    cmd.args(["copy", "--to", &to.to]).arg(farm);
+    run(cmd, "nix copy", opts)?;
+    say!("Published: {} → {}", farm.display(), to.to);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_the_command_this_nix_has() {
+        assert_eq!(sign_args(Some((2, 3, 16))), ["sign-paths"]);
+        assert_eq!(sign_args(Some((2, 24, 9))), ["store", "sign"]);
+        assert_eq!(sign_args(None), ["store", "sign"]);
+    }
+
+    /// Needs a working Nix; skipped where there is none.
+    #[test]
+    fn copies_to_a_file_binary_cache() {
+        let install = NixInstall::get();
+        if install.nix.is_none() || install.nix_store.is_none() {
+            eprintln!("skipping: no Nix installation");
+            return;
+        }
+        let _serial = crate::process::tests::SERIAL
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("nix-opengl-driver-publish-test");
+        std::fs::write(&file, "published\n").unwrap();
+        let out = install
+            .nix_store_command()
+            .arg("--add")
+            .arg(&file)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        let path = String::from_utf8(out.stdout).unwrap().trim().to_string();
+
+        let cache = tmp.path().join("cache");
+        let to = Publish {
+            to: format!("file://{}", cache.display()),
+            secret_key: None,
+        };
+        publish(Path::new(&path), &to, &BuildOptions::default()).unwrap();
+
+        let hash = path
+            .trim_start_matches("/nix/store/")
+            .split('-')
+            .next()
+            .unwrap();
+        assert!(cache.join("nix-cache-info").exists());
+        assert!(cache.join(format!("{hash}.narinfo")).exists());
+    }
+}
@@ -3,11 +3,13 @@ use handlebars::Handlebars;
 use serde::Serialize;
 use std::{env, fs, process::Command};
 
+use crate::nix_probe::NixInstall;
+use crate::output::{report, say};
 use crate::utils::pin_store_path;
 
 const GCROOT_TOOL: &str = "/nix/var/nix/gcroots/nix-opengl-driver/tool";
 const SERVICE_NAME: &str = "nix-opengl-driver.service";
-const SERVICE_PATH: &str = "/etc/systemd/system/nix-opengl-driver.service";
+pub const SERVICE_PATH: &str = "/etc/systemd/system/nix-opengl-driver.service";
 
 fn tool_path() -> Result<String> {
     let exe = env::current_exe().context("getting current exe path")?;
@@ -61,7 +63,9 @@ fn render_service() -> Result<(String, String)> {
 
 pub fn print_service() -> Result<()> {
     let (_, service_unit) = render_service().context("printing service")?;
-    println!("{}", service_unit);
+    report(&serde_json::json!({ "unit": service_unit }), || {
+        println!("{}", service_unit)
+    });
 
     Ok(())
 }
@@ -85,7 +89,7 @@ pub fn install_service(quiet: bool) -> Result<()> {
         .status()
         .context("enabling sync service")?;
 
-    println!("Installed service pointing at {}", tool_path);
+    say!("Installed service pointing at {}", tool_path);
     Ok(())
 }
 
@@ -110,11 +114,12 @@ pub fn uninstall_service() -> Result<()> {
         .status()
         .context("running systemctl daemon-reload")?;
 
-    let _ = Command::new("nix-store")
+    let _ = NixInstall::get()
+        .nix_store_command()
         .args(["--delete-root", GCROOT_TOOL])
         .status();
 
-    println!("Uninstalled {}", SERVICE_NAME);
+    say!("Uninstalled {}", SERVICE_NAME);
     Ok(())
 }
 
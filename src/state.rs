@@ -1,42 +1,526 @@
 use crate::detect::Driver;
-use chrono::Utc;
+use crate::generations::GCROOT_DIR;
+use crate::hardware::Hardware;
+use crate::lock::{lock_path, write_atomic, FileLock};
+use crate::output::Done;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use serde_json::{json, Value};
+use std::{fs, io::ErrorKind, path::Path};
 
 pub const GCROOT_SYMLINK: &str = "/nix/var/nix/gcroots/nix-opengl-driver/current";
 pub const STATE_FILE: &str = "/var/lib/nix-opengl-driver/state.json";
 pub const STATE_BAK: &str = "/var/lib/nix-opengl-driver/state.json.bak";
 
-#[derive(Serialize, Deserialize)]
+/// Layout of `state.json`; bump it and add a step to [`migrate`] when
+/// changing fields in a way older files can't just default.
+///
+/// 1. `detected`, `active` and `last_sync` only, no `schema_version`.
+/// 2. Fingerprint, generations, rollback hold and last failure.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// A state file written by a newer version of the tool.
+#[derive(Debug, thiserror::Error)]
+#[error("state schema {0} is newer than this tool's ({SCHEMA_VERSION}); refusing to touch it")]
+pub struct TooNew(u32);
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct State {
+    #[serde(default)]
+    pub schema_version: u32,
     pub detected: String,
     pub active: String,
     pub last_sync: String,
+    /// Inputs of the active farm; absent in state written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Fingerprint>,
+    /// Every farm kept alive by its own GC root, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub generations: Vec<Generation>,
+    /// Set by `rollback`: `sync --if-changed` leaves the farm alone unless
+    /// the driver or hardware changed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub held: bool,
+    /// The most recent sync that failed, cleared by the next good one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<Failure>,
+    /// How the active farm was built.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// Everything needed to tell what a farm contains without looking at it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Provenance {
+    pub tool_version: String,
+    /// SRI hash of the rendered Nix expression.
+    pub expr: String,
+    pub nixpkgs: Option<String>,
+    /// `(name, version)` of each package linked into the farm.
+    pub packages: Vec<(String, String)>,
+    pub nvidia_hash: Option<String>,
+    pub build_seconds: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Failure {
+    pub time: String,
+    /// The driver the failed sync tried to build for.
+    pub driver: String,
+    pub error: String,
+}
+
+/// One farm that was switched to, see [`crate::generations`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Generation {
+    pub number: u32,
+    pub path: String,
+    pub driver: String,
+    pub created: String,
+    #[serde(default)]
+    pub nixpkgs: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Fingerprint>,
+    /// Restored by `rollback`, so the state describes the farm it switched to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// Everything that decides what `sync` would build. If it hasn't changed and
+/// the farm is still there, there's nothing to do.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// SRI hash of the rendered Nix expression.
+    pub expr: String,
+    pub driver: String,
+    pub nixpkgs: Option<String>,
+    pub hardware: Hardware,
+}
+
+impl Fingerprint {
+    /// Why a farm built for `self` doesn't fit `new`, one line each.
+    pub fn changes(&self, new: &Fingerprint) -> Vec<String> {
+        let mut out = Vec::new();
+        if self.driver != new.driver {
+            out.push(format!("Driver: {} → {}", self.driver, new.driver));
+        }
+        if self.nixpkgs != new.nixpkgs {
+            let rev = |r: &Option<String>| r.clone().unwrap_or_else(|| "<unknown>".into());
+            out.push(format!(
+                "nixpkgs: {} → {}",
+                rev(&self.nixpkgs),
+                rev(&new.nixpkgs)
+            ));
+        }
+        out.extend(self.hardware.changes(&new.hardware));
+        if self.expr != new.expr && out.is_empty() {
+            out.push("Nix expression changed".into());
+        }
+        out
+    }
 }
 
 impl State {
     pub fn load() -> Option<Self> {
-        let txt = fs::read_to_string(STATE_FILE)
-            .or_else(|_| fs::read_to_string(STATE_BAK))
-            .ok()?;
-        serde_json::from_str(&txt).ok()
+        // best effort, and read-only: no lock file means no state either
+        let _lock = FileLock::shared(&lock_path(Path::new(STATE_FILE))).ok();
+        read_any(Path::new(STATE_FILE), Path::new(STATE_BAK))
+            .ok()
+            .flatten()
+            .map(|(s, _)| s)
     }
 
-    pub fn save(d: &Driver, active: &Path) -> std::io::Result<()> {
-        let s = State {
-            detected: match d {
-                Driver::Nvidia(v) => format!("nvidia {}", v),
-                Driver::Mesa => "mesa".into(),
-            },
-            active: active.display().to_string(),
-            last_sync: Utc::now().to_rfc3339(),
+    /// Record a successful sync to `active`.
+    pub fn save(
+        d: &Driver,
+        active: &Path,
+        fingerprint: Option<Fingerprint>,
+        provenance: Option<Provenance>,
+    ) -> Result<()> {
+        Self::update(|s| {
+            s.provenance = provenance;
+            s.detected = d.to_string();
+            s.active = active.display().to_string();
+            s.last_sync = Utc::now().to_rfc3339();
+            s.fingerprint = fingerprint;
+            s.held = false;
+            s.last_failure = None;
+        })
+    }
+
+    /// Note a failed sync; the active farm stays as it was.
+    pub fn record_failure(d: &Driver, error: &anyhow::Error) -> Result<()> {
+        Self::update(|s| s.failed(d, error))
+    }
+
+    /// [`State::record_failure`] in memory.
+    pub fn failed(&mut self, d: &Driver, error: &anyhow::Error) {
+        self.last_failure = Some(Failure {
+            time: Utc::now().to_rfc3339(),
+            driver: d.to_string(),
+            error: format!("{error:#}"),
+        });
+    }
+
+    /// Read, modify and write back the state under an exclusive lock.
+    pub fn update<T>(f: impl FnOnce(&mut State) -> T) -> Result<T> {
+        let path = Path::new(STATE_FILE);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let _lock = FileLock::exclusive(&lock_path(path))?;
+        let (mut s, from_primary) = match read_any(path, Path::new(STATE_BAK)) {
+            Ok(Some(found)) => found,
+            Ok(None) => (State::default(), false),
+            Err(e) if e.is::<TooNew>() => return Err(e),
+            Err(e) => {
+                warn!("discarding unreadable state: {e:#}");
+                (State::default(), false)
+            }
         };
+        let out = f(&mut s);
+        s.schema_version = SCHEMA_VERSION;
         let json = serde_json::to_string_pretty(&s)?;
-        let tmp = format!("{}.tmp", STATE_FILE);
-        fs::create_dir_all(Path::new(STATE_FILE).parent().unwrap())?;
-        fs::write(&tmp, &json)?;
-        fs::rename(STATE_FILE, STATE_BAK).ok();
-        fs::rename(tmp, STATE_FILE)?;
-        Ok(())
+
+        // Keep the previous state as a backup without ever leaving
+        // STATE_FILE missing: both files are replaced atomically. A corrupt
+        // primary doesn't get to overwrite a good backup.
+        if from_primary {
+            write_atomic(Path::new(STATE_BAK), &fs::read(path)?)?;
+        }
+        write_atomic(path, json.as_bytes())?;
+        Ok(out)
+    }
+}
+
+/// Parse a state file of any schema, migrating it to the current one.
+fn parse(txt: &str) -> Result<State> {
+    let v: Value = serde_json::from_str(txt)?;
+    Ok(serde_json::from_value(migrate(v)?)?)
+}
+
+fn migrate(mut v: Value) -> Result<Value> {
+    let version = v
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .map_or(1, |n| n as u32);
+    if version > SCHEMA_VERSION {
+        return Err(TooNew(version).into());
+    }
+    if version < 2 {
+        v1_to_v2(&mut v);
+    }
+    v["schema_version"] = SCHEMA_VERSION.into();
+    Ok(v)
+}
+
+/// Version 1 predates generations: the active farm becomes generation 1.
+fn v1_to_v2(v: &mut Value) {
+    let active = v["active"].as_str().unwrap_or_default();
+    if v.get("generations").is_none() && !active.is_empty() {
+        v["generations"] = json!([{
+            "number": 1,
+            "path": active,
+            "driver": v["detected"],
+            "created": v["last_sync"],
+        }]);
+    }
+}
+
+/// `Ok(None)` if `path` doesn't exist.
+fn read_file(path: &Path) -> Result<Option<State>> {
+    match fs::read_to_string(path) {
+        Ok(txt) => parse(&txt)
+            .map(Some)
+            .with_context(|| format!("reading {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// The primary file, or the backup if the primary is missing or corrupt.
+/// The flag tells whether the state came from the primary.
+fn read_any(primary: &Path, backup: &Path) -> Result<Option<(State, bool)>> {
+    let err = match read_file(primary) {
+        Ok(Some(s)) => return Ok(Some((s, true))),
+        Ok(None) => None,
+        Err(e) if e.is::<TooNew>() => return Err(e),
+        Err(e) => {
+            warn!("{e:#}; trying {}", backup.display());
+            Some(e)
+        }
+    };
+    match (read_file(backup)?, err) {
+        (Some(s), _) => Ok(Some((s, false))),
+        (None, Some(e)) => Err(e),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Rebuild `state.json` from the GC roots after both files were lost.
+pub fn repair() -> Result<()> {
+    let path = Path::new(STATE_FILE);
+    match read_any(path, Path::new(STATE_BAK)) {
+        Ok(Some((_, true))) => {
+            Done::report(format!("State is fine: {STATE_FILE}"));
+            return Ok(());
+        }
+        Ok(Some((_, false))) => {
+            // rewriting it restores the primary from the backup
+            State::update(|_| ())?;
+            Done::report(format!("Restored {STATE_FILE} from {STATE_BAK}"));
+            return Ok(());
+        }
+        Err(e) if e.is::<TooNew>() => return Err(e),
+        _ => {}
+    }
+
+    let active = fs::read_link(GCROOT_SYMLINK)
+        .with_context(|| format!("no GC root at {GCROOT_SYMLINK}; run `sync` instead"))?;
+    let active = active.display().to_string();
+    let mut generations = Vec::new();
+    if let Ok(entries) = fs::read_dir(GCROOT_DIR) {
+        for e in entries.flatten() {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let Some(number) = name
+                .strip_prefix("generation-")
+                .and_then(|n| n.parse().ok())
+            else {
+                continue;
+            };
+            let Ok(target) = fs::read_link(e.path()) else {
+                continue;
+            };
+            let target = target.display().to_string();
+            generations.push(Generation {
+                number,
+                driver: farm_driver(Path::new(&target)),
+                path: target,
+                created: link_time(&e.path()),
+                nixpkgs: None,
+                fingerprint: None,
+                provenance: None,
+            });
+        }
+    }
+    generations.sort_by_key(|g| g.number);
+
+    let s = State {
+        schema_version: SCHEMA_VERSION,
+        detected: farm_driver(Path::new(&active)),
+        last_sync: link_time(Path::new(GCROOT_SYMLINK)),
+        active,
+        generations,
+        ..State::default()
+    };
+    fs::create_dir_all(path.parent().unwrap())?;
+    let _lock = FileLock::exclusive(&lock_path(path))?;
+    write_atomic(path, serde_json::to_string_pretty(&s)?.as_bytes())?;
+    Done::report(format!(
+        "Rebuilt {STATE_FILE}: {} ({}), {} generation(s)",
+        s.active,
+        s.detected,
+        s.generations.len()
+    ));
+    Ok(())
+}
+
+/// When a GC-root symlink was last switched.
+fn link_time(link: &Path) -> String {
+    fs::symlink_metadata(link)
+        .and_then(|m| m.modified())
+        .map(|t| DateTime::<Utc>::from(t).to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// Which driver a farm was built for, by its NVIDIA libraries.
+pub fn farm_driver(farm: &Path) -> String {
+    let nvidia = fs::read_dir(farm.join("lib")).ok().and_then(|entries| {
+        entries.flatten().find_map(|e| {
+            let name = e.file_name().to_string_lossy().into_owned();
+            name.strip_prefix("libnvidia-glcore.so.")
+                .map(str::to_string)
+        })
+    });
+    match nvidia {
+        Some(v) => Driver::Nvidia(v).to_string(),
+        None => Driver::Mesa.to_string(),
+    }
+}
+
+/// Store path of the farm currently in use: the GC root's target, or what
+/// the state file recorded if the root is gone.
+pub fn active_farm() -> Option<String> {
+    fs::read_link(GCROOT_SYMLINK)
+        .ok()
+        .map(|p| p.display().to_string())
+        .or_else(|| State::load().map(|s| s.active))
+}
+
+/// Whether the active farm was built from exactly these inputs and is still
+/// in the store. After a rollback only driver and hardware changes count.
+pub fn is_current(fingerprint: &Fingerprint) -> bool {
+    State::load().is_some_and(|s| matches(&s, fingerprint, Path::new(GCROOT_SYMLINK)))
+}
+
+/// [`is_current`] for state `s` and the GC root at `gcroot`.
+fn matches(s: &State, fingerprint: &Fingerprint, gcroot: &Path) -> bool {
+    let same = if s.held {
+        s.detected == fingerprint.driver
+            && s.fingerprint
+                .as_ref()
+                .is_none_or(|old| old.hardware == fingerprint.hardware)
+    } else {
+        s.fingerprint.as_ref() == Some(fingerprint)
+    };
+    same && fs::read_link(gcroot).is_ok_and(|target| target.exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::Gpu;
+    use std::{os::unix::fs::symlink, path::PathBuf};
+
+    fn fingerprint() -> Fingerprint {
+        Fingerprint {
+            expr: "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".into(),
+            driver: "nvidia 570.133.07".into(),
+            nixpkgs: Some("5ed627539ac84809c78b2dd6d26a5cebeb5ae269".into()),
+            hardware: Hardware {
+                gpus: vec![Gpu {
+                    slot: "0000:01:00.0".into(),
+                    id: "10de:2684".into(),
+                    module: Some("nvidia".into()),
+                }],
+                modules: [("nvidia".into(), "570.133.07".into())].into(),
+                kernel: "6.6.30".into(),
+            },
+        }
+    }
+
+    /// State for a sync from [`fingerprint`] and a GC root to a farm in `dir`.
+    fn synced(dir: &Path) -> (State, PathBuf) {
+        let farm = dir.join("farm");
+        fs::create_dir(&farm).unwrap();
+        let gcroot = dir.join("current");
+        symlink(&farm, &gcroot).unwrap();
+        let s = State {
+            detected: "nvidia 570.133.07".into(),
+            fingerprint: Some(fingerprint()),
+            ..State::default()
+        };
+        (s, gcroot)
+    }
+
+    #[test]
+    fn unchanged_inputs_skip_the_sync() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (s, gcroot) = synced(tmp.path());
+        assert!(matches(&s, &fingerprint(), &gcroot));
+    }
+
+    #[test]
+    fn any_changed_input_rebuilds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (s, gcroot) = synced(tmp.path());
+        let changed: [fn(&mut Fingerprint); 4] = [
+            |f| f.driver = "nvidia 575.51.02".into(),
+            |f| f.expr = "sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU=".into(),
+            |f| f.nixpkgs = None,
+            |f| f.hardware.kernel = "6.6.31".into(),
+        ];
+        for change in changed {
+            let mut fp = fingerprint();
+            change(&mut fp);
+            assert!(!matches(&s, &fp, &gcroot), "{fp:?}");
+        }
+        // state from before fingerprints were recorded
+        let old = State {
+            fingerprint: None,
+            ..s
+        };
+        assert!(!matches(&old, &fingerprint(), &gcroot));
+    }
+
+    #[test]
+    fn a_collected_farm_rebuilds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (s, gcroot) = synced(tmp.path());
+        fs::remove_dir(tmp.path().join("farm")).unwrap();
+        assert!(!matches(&s, &fingerprint(), &gcroot));
+        assert!(!matches(&s, &fingerprint(), &tmp.path().join("missing")));
+    }
+
+    #[test]
+    fn a_held_farm_only_rebuilds_for_driver_or_hardware() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (mut s, gcroot) = synced(tmp.path());
+        s.held = true;
+
+        let mut fp = fingerprint();
+        fp.expr = "sha256-WlQWo6iKAMSsGRmS26id8pwyTK0G2+sYQlIRgcdsoFU=".into();
+        fp.nixpkgs = None;
+        assert!(matches(&s, &fp, &gcroot));
+
+        fp.hardware.gpus.clear();
+        assert!(!matches(&s, &fp, &gcroot));
+        let mut fp = fingerprint();
+        fp.driver = "nvidia 575.51.02".into();
+        assert!(!matches(&s, &fp, &gcroot));
+    }
+
+    #[test]
+    fn migrates_the_original_layout() {
+        let s = parse(
+            r#"{
+                "detected": "nvidia 570.133.07",
+                "active": "/nix/store/r0ahk6mdw9mr5gky8g4dn9z4v0f6n4sj-nix-opengl-driver",
+                "last_sync": "2025-05-01T08:00:00+00:00"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(s.schema_version, SCHEMA_VERSION);
+        assert_eq!(s.generations.len(), 1);
+        assert_eq!(s.generations[0].path, s.active);
+        assert_eq!(s.generations[0].driver, "nvidia 570.133.07");
+
+        let newer =
+            parse(r#"{"schema_version": 99, "detected": "", "active": "", "last_sync": ""}"#);
+        assert!(newer.is_err_and(|e| e.is::<TooNew>()));
+    }
+
+    #[test]
+    fn corrupt_primary_falls_back_to_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let (primary, backup) = (
+            dir.path().join("state.json"),
+            dir.path().join("state.json.bak"),
+        );
+        fs::write(&primary, "{ not json").unwrap();
+        fs::write(
+            &backup,
+            r#"{"detected": "mesa", "active": "/nix/store/a", "last_sync": ""}"#,
+        )
+        .unwrap();
+
+        let (s, from_primary) = read_any(&primary, &backup).unwrap().unwrap();
+        assert_eq!(s.detected, "mesa");
+        assert!(!from_primary);
+
+        fs::remove_file(&backup).unwrap();
+        assert!(read_any(&primary, &backup).is_err());
+        fs::remove_file(&primary).unwrap();
+        assert!(read_any(&primary, &backup).unwrap().is_none());
+    }
+
+    #[test]
+    fn tells_nvidia_farms_from_mesa_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("lib")).unwrap();
+        assert_eq!(farm_driver(dir.path()), "mesa");
+        fs::write(dir.path().join("lib/libnvidia-glcore.so.570.133.07"), "").unwrap();
+        assert_eq!(farm_driver(dir.path()), "nvidia 570.133.07");
     }
 }
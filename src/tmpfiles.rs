@@ -1,3 +1,4 @@
+use crate::output::{report, say};
 use crate::state::GCROOT_SYMLINK;
 use anyhow::{bail, Context as _, Result};
 use std::{fs, io::ErrorKind, process::Command};
@@ -7,7 +8,8 @@ pub const TMPFILES_CONF: &str = "/etc/tmpfiles.d/nix-opengl-driver.conf";
 
 /// Print the tmpfiles.d rule
 pub fn print_rule() {
-    println!("L {} - - - - {}", RUN_SYMLINK, GCROOT_SYMLINK);
+    let rule = format!("L {} - - - - {}", RUN_SYMLINK, GCROOT_SYMLINK);
+    report(&serde_json::json!({ "rule": rule }), || println!("{rule}"));
 }
 
 /// Install `/etc/tmpfiles.d/nix-opengl-driver.conf`
@@ -15,12 +17,12 @@ pub fn install_rule() -> Result<()> {
     let rule = format!("L {} - - - - {}\n", RUN_SYMLINK, GCROOT_SYMLINK);
     fs::write(TMPFILES_CONF, rule)
         .with_context(|| format!("writing tmpfiles rule to {}", TMPFILES_CONF))?;
-    println!("Installed tmpfiles.d rule.");
+    say!("Installed tmpfiles.d rule.");
     Command::new("systemd-tmpfiles")
         .args(["--create", "/etc/tmpfiles.d/nix-opengl-driver.conf"])
         .status()
         .context("applying tmpfiles rule")?;
-    println!("/run/opengl-driver is now populated.");
+    say!("/run/opengl-driver is now populated.");
     Ok(())
 }
 
@@ -1,15 +1,97 @@
-use std::{fs, os::unix::fs as unix_fs, path::Path};
+use std::{
+    fs::{self, File},
+    os::unix::fs as unix_fs,
+    path::Path,
+};
 
 use anyhow::Context as _;
 
+/// Point the `gcroot` symlink at `store_path`. The new link is created under
+/// a temporary name and renamed over the old one, so the root (and
+/// `/run/opengl-driver`, which points at it) never goes missing.
 pub fn pin_store_path(store_path: &str, gcroot: &str) -> anyhow::Result<()> {
     let gcroot = Path::new(gcroot);
+    let dir = gcroot.parent().unwrap_or(Path::new("."));
 
-    if let Some(dir) = gcroot.parent() {
-        fs::create_dir_all(dir).context("creating GC-root directory")?;
-    }
-    let _ = fs::remove_file(gcroot);
-    unix_fs::symlink(store_path, gcroot).context("creating GC-root symlink")?;
+    fs::create_dir_all(dir).context("creating GC-root directory")?;
+    let tmp = tempfile::Builder::new()
+        .prefix(".")
+        .suffix(".tmp")
+        .make_in(dir, |p| unix_fs::symlink(store_path, p))
+        .context("creating GC-root symlink")?;
+    tmp.persist(gcroot)
+        .map_err(|e| e.error)
+        .context("switching GC-root symlink")?;
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context("syncing GC-root directory")?;
 
     Ok(())
 }
+
+/// Whether any interface has a default route, i.e. downloads stand a chance.
+pub fn has_default_route() -> bool {
+    let v4 = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let v6 = fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    v4.lines()
+        .skip(1)
+        .any(|l| l.split_whitespace().nth(1) == Some("00000000"))
+        || v6.lines().any(|l| {
+            let f: Vec<_> = l.split_whitespace().collect();
+            f.len() == 10 && f[0].bytes().all(|b| b == b'0') && f[1] == "00" && f[9] != "lo"
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    #[test]
+    fn pin_replaces_the_link_without_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("gcroots/current");
+
+        pin_store_path("/nix/store/a", root.to_str().unwrap()).unwrap();
+        pin_store_path("/nix/store/b", root.to_str().unwrap()).unwrap();
+
+        assert_eq!(fs::read_link(&root).unwrap(), Path::new("/nix/store/b"));
+        assert_eq!(fs::read_dir(root.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn pin_never_leaves_the_link_missing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("current");
+        let root = root.to_str().unwrap();
+        pin_store_path("/nix/store/a", root).unwrap();
+
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) {
+                    fs::read_link(root).expect("GC root vanished during a switch");
+                    reads += 1;
+                }
+                reads
+            });
+            for i in 0..500 {
+                pin_store_path(
+                    if i % 2 == 0 {
+                        "/nix/store/b"
+                    } else {
+                        "/nix/store/a"
+                    },
+                    root,
+                )
+                .unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+            assert!(reader.join().unwrap() > 0);
+        });
+    }
+}